@@ -1,27 +1,617 @@
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Write};
+use std::fs::{self, File, OpenOptions};
+use std::collections::VecDeque;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, Mutex};
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderValue};
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+use tokio_tungstenite::{client_async_with_config, tungstenite::Message, MaybeTlsStream};
+use socket2::{SockRef, TcpKeepalive};
+use sha2::{Digest, Sha256};
+use snow::params::NoiseParams;
 use snow::{Builder, HandshakeState, TransportState};
 use std::error::Error;
 
-const NOISE_PATTERN: &str = "Noise_XXpsk2_25519_AESGCM_SHA256";
+// Noise protocol components, assembled into Noise_<pattern>_<dh>_<cipher>_<hash>.
+// Must match the other side
+const HANDSHAKE_PATTERN: &str = "XXpsk2";
+const DH_FUNCTION: &str = "25519";
+const CIPHER: &str = "AESGCM";  // or "ChaChaPoly"
+const HASH: &str = "SHA256";  // or "SHA512", "BLAKE2s", "BLAKE2b"
+// Full protocol name, used instead of the components above when set
+const NOISE_PATTERN_OVERRIDE: Option<&str> = None;  // e.g. Some("Noise_XXpsk2_25519_ChaChaPoly_BLAKE2s")
 const PSK: &[u8; 32] = b"my_super_secret_pre_shared_key!!";
+// Send small messages immediately instead of letting Nagle's algorithm batch
+// them, trading a little bandwidth for latency
+const TCP_NODELAY: bool = true;
+// Probe idle connections so dead peers are noticed; None keeps the OS default
+const TCP_KEEPALIVE_SECS: Option<u64> = None;  // e.g. Some(60)
+// Kernel send and receive buffer size per connection; None keeps the OS default
+const SOCKET_BUFFER_SIZE: Option<usize> = None;
+// Reach the server through this SOCKS5 proxy, e.g. Tor; the proxy resolves the
+// server's host name, so no DNS query leaves this machine
+const SOCKS5_PROXY: Option<&str> = None;  // e.g. Some("127.0.0.1:9050")
+const SOCKS5_AUTH: Option<(&str, &str)> = None;  // (username, password)
+// Control types this version doesn't know (from a newer server) are shown
+// using their plain-text fallback; set to drop such messages instead
+const REJECT_UNKNOWN_CONTROL: bool = false;
+// Print extra diagnostics (never secrets) when something goes wrong
+const DEBUG_LOGGING: bool = false;
+// WebSocket subprotocol naming this wire protocol; must match the server
+const SUBPROTOCOL: &str = "noise-chat-v2";
+// Upgrade request header carrying the client's full Noise protocol name. It is
+// sent outside Noise because with a PSK pattern even the first handshake
+// payload is encrypted, so a mismatch could otherwise only show up as a failed decrypt
+const NOISE_PROTOCOL_HEADER: &str = "x-noise-protocol";
+// Noise caps every message at 65535 bytes; each WebSocket message carries exactly one
+const NOISE_MAX_MESSAGE_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+// Largest payload either side puts in a handshake message: SUBPROTOCOL in the
+// second and third. The first is empty, as the initiator's Noise protocol name
+// travels in the NOISE_PROTOCOL_HEADER upgrade header instead
+const MAX_HANDSHAKE_PAYLOAD_LEN: usize = 64;
+// First plaintext byte of a frame carrying several coalesced messages
+const BATCH_MARKER: u8 = 0;
+// Persistent static keypair; None generates a fresh one for every connection
+const STATIC_KEY_FILE: Option<&str> = None;  // e.g. Some("client_static.key")
+// Join as a read-only observer instead of a participant; the server's OBSERVER_TOKEN
+const OBSERVER_TOKEN: Option<&str> = None;
+// Append every message sent and received to this file as JSON lines. It holds
+// decrypted plaintext, so protect it like the conversation itself
+const MESSAGE_LOG_FILE: Option<&str> = None;  // e.g. Some("messages.jsonl")
+// Past this size the log is moved to "<file>.1" and a new one started
+const MESSAGE_LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+// Whether server announcements (/sys) go into the message log too
+const LOG_SYSTEM_NOTICES: bool = true;
+// After a disconnect the server marks as reconnectable, try the same server
+// this many times; 0 exits instead. The delay doubles after each attempt
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_DELAY_SECS: u64 = 3;
+// A session that stayed up this long starts the attempt count over
+const RECONNECT_STABLE_SECS: u64 = 60;
+// Sent broadcasts remembered for matching the server's echo of them
+const MAX_PENDING_ECHOES: usize = 64;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct ChatMessage {
     sender: String,
     content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    control: Option<ControlMessage>,
+    // Structured payload; `content` then holds a plain-text summary
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rich: Option<RichPayload>,
+    // Assigned by the server to every relayed client message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    meta: Option<MessageMeta>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct MessageMeta {
+    id: u64,
+    timestamp_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RichPayload {
+    content_type: String,
+    data: serde_json::Value,
+}
+
+impl RichPayload {
+    // Only JSON payloads are rendered; anything else shows the sender's summary
+    fn render(&self) -> Option<String> {
+        if self.content_type == "application/json" || self.content_type.ends_with("+json") {
+            Some(format!("[{}] {}", self.content_type, self.data))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ControlMessage {
+    RosterSnapshot { version: u64, names: Vec<String> },
+    RosterJoin { version: u64, name: String },
+    RosterLeave { version: u64, name: String },
+    RosterRename { version: u64, old: String, new: String },
+    Rename { name: String },
+    Direct { to: String },
+    Admin { token: String, command: String },
+    Observe { token: String },
+    Disconnect { reason: DisconnectReason, reconnect: bool },
+    Redirect { url: String },
+    System,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum DisconnectReason {
+    RateLimited,
+    TooSlow,
+    InvalidName,
+    Banned,
+    Unauthorized,
+    Shutdown,
+}
+
+impl DisconnectReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            DisconnectReason::RateLimited => "rate limited",
+            DisconnectReason::TooSlow => "too slow",
+            DisconnectReason::InvalidName => "invalid name",
+            DisconnectReason::Banned => "banned",
+            DisconnectReason::Unauthorized => "unauthorized",
+            DisconnectReason::Shutdown => "server shutting down",
+        }
+    }
+}
+
+// Broadcasts sent but not yet seen again, for matching the server's echo of
+// them (its ECHO_OWN_MESSAGES) against what was typed. Bounded, since a server
+// without echo never clears it
+#[derive(Default)]
+struct PendingEchoes {
+    sent: VecDeque<String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Echo {
+    // Someone else's message, or one this client didn't send
+    NotOwn,
+    // Relayed as typed
+    Confirmed(u64),
+    // Relayed, but changed on the way (e.g. masked by the server's filter)
+    Altered(u64),
+}
+
+impl PendingEchoes {
+    fn sent(&mut self, content: &str) {
+        if self.sent.len() >= MAX_PENDING_ECHOES {
+            self.sent.pop_front();
+        }
+        self.sent.push_back(content.to_string());
+    }
+
+    fn reconcile(&mut self, msg: &ChatMessage, my_name: Option<&str>) -> Echo {
+        let Some(meta) = &msg.meta else {
+            return Echo::NotOwn;
+        };
+        if msg.control.is_some() || my_name != Some(msg.sender.as_str()) || self.sent.is_empty() {
+            return Echo::NotOwn;
+        }
+        // Anything sent before it that never came back was dropped by the server
+        match self.sent.iter().position(|content| *content == msg.content) {
+            Some(pos) => {
+                self.sent.drain(..=pos);
+                Echo::Confirmed(meta.id)
+            }
+            None => {
+                self.sent.pop_front();
+                Echo::Altered(meta.id)
+            }
+        }
+    }
+}
+
+/// What to show for a message whose `type` this client doesn't know: its
+/// plain-text fallback, or nothing when `reject` (REJECT_UNKNOWN_CONTROL) is set.
+fn unknown_control_line(msg: &ChatMessage, reject: bool) -> Option<String> {
+    (!reject).then(|| format!("{}: {}", msg.sender, msg.content))
+}
+
+/// Whether an incoming message goes into the message log; server
+/// announcements only do when `log_system` (LOG_SYSTEM_NOTICES) is set.
+fn logs_incoming(msg: &ChatMessage, log_system: bool) -> bool {
+    log_system || !matches!(msg.control, Some(ControlMessage::System))
+}
+
+/// How a server announcement (/sys) is shown, set apart from the chat.
+fn system_notice_line(msg: &ChatMessage) -> String {
+    format!("*** {} ***", msg.content)
+}
+
+// Live view of who is online, kept in sync by the server's roster messages
+#[derive(Default)]
+struct Roster {
+    version: u64,
+    names: Vec<String>,
+}
+
+impl Roster {
+    /// Applies a roster update, returning false if it was already reflected
+    /// in an earlier snapshot and should be ignored.
+    fn apply(&mut self, control: ControlMessage) -> bool {
+        match control {
+            ControlMessage::RosterSnapshot { version, names } => {
+                self.version = version;
+                self.names = names;
+            }
+            ControlMessage::RosterJoin { version, name } => {
+                if version <= self.version {
+                    return false;
+                }
+                self.version = version;
+                self.names.push(name);
+                self.names.sort();
+            }
+            ControlMessage::RosterLeave { version, name } => {
+                if version <= self.version {
+                    return false;
+                }
+                self.version = version;
+                if let Some(pos) = self.names.iter().position(|n| n == &name) {
+                    self.names.remove(pos);
+                }
+            }
+            ControlMessage::RosterRename { version, old, new } => {
+                if version <= self.version {
+                    return false;
+                }
+                self.version = version;
+                if let Some(pos) = self.names.iter().position(|n| n == &old) {
+                    self.names[pos] = new;
+                    self.names.sort();
+                }
+            }
+            ControlMessage::Rename { .. }
+            | ControlMessage::Direct { .. }
+            | ControlMessage::Admin { .. }
+            | ControlMessage::Observe { .. }
+            | ControlMessage::Disconnect { .. }
+            | ControlMessage::Redirect { .. }
+            | ControlMessage::System
+            | ControlMessage::Unknown => {}
+        }
+        true
+    }
+}
+
+/// Splits a decrypted frame into its messages; frames that aren't batches hold one.
+fn unpack_batch(plaintext: Vec<u8>) -> Vec<Vec<u8>> {
+    if plaintext.first() != Some(&BATCH_MARKER) {
+        return vec![plaintext];
+    }
+
+    let mut messages = Vec::new();
+    let mut rest = &plaintext[1..];
+    while rest.len() >= 4 {
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        rest = &rest[4..];
+        if len > rest.len() {
+            eprintln!("Truncated message batch");
+            break;
+        }
+        messages.push(rest[..len].to_vec());
+        rest = &rest[len..];
+    }
+    messages
+}
+
+// Plaintext record of the conversation as this client saw it, for MESSAGE_LOG_FILE
+struct MessageLog {
+    path: String,
+    file: File,
+    len: u64,
+}
+
+impl MessageLog {
+    /// Opens the log for appending. It holds plaintext, so on Unix it is kept
+    /// owner-only like the static key; a rotated copy keeps the same mode.
+    fn open(path: &str) -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options.open(path)?;
+        // The mode only applies on creation; tighten a log left by an older version
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        let len = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_string(),
+            file,
+            len,
+        })
+    }
+
+    /// Appends one message; `direction` is "in" or "out".
+    fn record(&mut self, direction: &str, message: &ChatMessage) -> io::Result<()> {
+        self.record_within(direction, message, MESSAGE_LOG_MAX_BYTES)
+    }
+
+    fn record_within(&mut self, direction: &str, message: &ChatMessage, max_bytes: u64) -> io::Result<()> {
+        if self.len >= max_bytes {
+            let path = self.path.clone();
+            fs::rename(&path, format!("{}.1", path))?;
+            *self = Self::open(&path)?;
+        }
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let mut line = serde_json::to_vec(&serde_json::json!({
+            "timestamp_ms": timestamp_ms,
+            "direction": direction,
+            "message": message,
+        }))?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn log_message(log: &Option<Arc<std::sync::Mutex<MessageLog>>>, direction: &str, message: &ChatMessage) {
+    if let Some(log) = log {
+        if let Err(e) = log.lock().unwrap().record(direction, message) {
+            eprintln!("Failed to write message log: {}", e);
+        }
+    }
+}
+
+// What has been typed at the prompt but not sent yet. Only the line editor
+// fills it in; with plain line input the terminal keeps it to itself
+static TYPED_INPUT: std::sync::Mutex<String> = std::sync::Mutex::new(String::new());
+
+// Prints an incoming line above the "> " prompt instead of after it, then
+// redraws the prompt and whatever has been typed so far.
+fn print_incoming(line: &str, interactive: bool) {
+    if interactive {
+        let typed = TYPED_INPUT.lock().unwrap();
+        print!("{}", render_above_prompt(line, Some(&typed)));
+        let _ = io::stdout().flush();
+    } else {
+        println!("{}", line);
+    }
+}
+
+// Like print_incoming, for the line that ends a session; the next prompt
+// comes from the following session, if there is one.
+fn print_final(line: &str, interactive: bool) {
+    if interactive {
+        print!("{}", render_above_prompt(line, None));
+        let _ = io::stdout().flush();
+    } else {
+        println!("{}", line);
+    }
+}
+
+fn print_prompt() {
+    print!("{}", render_prompt(&TYPED_INPUT.lock().unwrap()));
+    let _ = io::stdout().flush();
+}
+
+fn render_prompt(typed: &str) -> String {
+    format!("> {}", typed)
+}
+
+/// Terminal output that replaces the prompt row with `line`, then redraws the
+/// prompt with `typed` below it; None leaves the cursor on a fresh line.
+fn render_above_prompt(line: &str, typed: Option<&str>) -> String {
+    // Return to column 0 and clear the row holding the prompt
+    let mut out = format!("\r\x1b[2K{}\n", line);
+    if let Some(typed) = typed {
+        out.push_str(&render_prompt(typed));
+    }
+    out
+}
+
+// Terminal settings from before the line editor started, restored on exit
+#[cfg(unix)]
+static SAVED_TERMINAL: std::sync::Mutex<Option<libc::termios>> = std::sync::Mutex::new(None);
+
+/// Minimal line editor for an interactive terminal. With canonical mode off
+/// the client sees each keystroke, so it knows the partly typed line and can
+/// redraw it after an incoming message. Output processing stays on, so "\n"
+/// still starts a new line everywhere else.
+#[cfg(unix)]
+fn start_line_editor(input_tx: mpsc::UnboundedSender<String>) -> io::Result<()> {
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut raw = termios;
+    raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+    raw.c_cc[libc::VMIN] = 1;
+    raw.c_cc[libc::VTIME] = 0;
+    if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    *SAVED_TERMINAL.lock().unwrap() = Some(termios);
+    std::thread::spawn(move || read_keys(input_tx));
+    Ok(())
+}
+
+#[cfg(unix)]
+fn restore_terminal() {
+    if let Some(termios) = SAVED_TERMINAL.lock().unwrap().take() {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) };
+    }
+}
+
+// Restores the terminal however main returns
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        restore_terminal();
+    }
+}
+
+/// Reads keystrokes into TYPED_INPUT, echoing them, and sends each line on
+/// Enter. Handles printable text, backspace, Ctrl-C and Ctrl-D on an empty
+/// line; escape sequences such as arrow keys are ignored.
+#[cfg(unix)]
+fn read_keys(input_tx: mpsc::UnboundedSender<String>) {
+    use std::io::Read;
+
+    let mut stdin = io::stdin().lock();
+    let mut byte = [0u8; 1];
+    // Bytes of a UTF-8 character that hasn't fully arrived
+    let mut partial = Vec::new();
+    let mut in_escape = false;
+    // Dropping input_tx on EOF or Ctrl-D tells the session to disconnect
+    while let Ok(1) = stdin.read(&mut byte) {
+        let mut typed = TYPED_INPUT.lock().unwrap();
+        let mut out = io::stdout();
+        match byte[0] {
+            // CSI sequences ("\x1b[" ...) end with a byte in 0x40..=0x7e
+            b if in_escape => in_escape = b == b'[' || !(0x40..=0x7e).contains(&b),
+            0x1b => in_escape = true,
+            b'\r' | b'\n' => {
+                let line = std::mem::take(&mut *typed);
+                let _ = writeln!(out);
+                let _ = out.flush();
+                drop(typed);
+                if input_tx.send(line).is_err() {
+                    return;
+                }
+                continue;
+            }
+            0x7f | 0x08 => {
+                if typed.pop().is_some() {
+                    let _ = write!(out, "\x08 \x08");
+                }
+            }
+            0x03 => {
+                restore_terminal();
+                println!();
+                std::process::exit(130);
+            }
+            0x04 if typed.is_empty() => return,
+            b if b < 0x20 => {}
+            b => {
+                partial.push(b);
+                match std::str::from_utf8(&partial) {
+                    Ok(text) => {
+                        typed.push_str(text);
+                        let _ = write!(out, "{}", text);
+                        partial.clear();
+                    }
+                    Err(e) if e.error_len().is_some() => partial.clear(),
+                    Err(_) => {}
+                }
+            }
+        }
+        let _ = out.flush();
+    }
+}
+
+const HELP: &str = "Commands:
+  /who                List clients currently online
+  /secinfo            Show what protects this session
+  /verify             Show the code to compare with the server operator
+  /rich <type> <json> Send a structured payload, e.g. /rich application/json {\"ok\":true}
+  /rename <name>      Change your name
+  /rotate-key         Replace the persistent static key
+  /admin <token> <cmd> Run a server console command, e.g. /admin s3cret /stats
+  /help               Show this list
+  /quit               Disconnect
+  @<name> <message>   Send a private message
+Anything else is sent to everyone. Start a line with // to send a leading /.";
+
+#[derive(Debug, PartialEq)]
+enum InputCommand {
+    Chat(String),
+    Direct { to: String, content: String },
+    Rich { content_type: String, data: serde_json::Value },
+    Who,
+    SecInfo,
+    Verify,
+    Rename(String),
+    Admin { token: String, command: String },
+    RotateKey,
+    Help,
+    Quit,
+    Invalid(String),
+}
+
+/// Parses a trimmed, non-empty input line.
+fn parse_input(line: &str) -> InputCommand {
+    if line.eq_ignore_ascii_case("quit") {
+        return InputCommand::Quit;
+    }
+
+    if let Some(rest) = line.strip_prefix("//") {
+        return InputCommand::Chat(format!("/{}", rest));
+    }
+
+    if let Some(rest) = line.strip_prefix('@') {
+        return match rest.split_once(' ') {
+            Some((to, content)) if !to.is_empty() && !content.trim().is_empty() => InputCommand::Direct {
+                to: to.to_string(),
+                content: content.trim().to_string(),
+            },
+            _ => InputCommand::Invalid("Invalid format. Use: @ClientName message".to_string()),
+        };
+    }
+
+    if let Some(rest) = line.strip_prefix('/') {
+        let (command, arg) = match rest.split_once(' ') {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (rest, ""),
+        };
+        return match command.to_ascii_lowercase().as_str() {
+            "who" => InputCommand::Who,
+            "secinfo" => InputCommand::SecInfo,
+            "verify" => InputCommand::Verify,
+            "rename" if !arg.is_empty() => InputCommand::Rename(arg.to_string()),
+            "rename" => InputCommand::Invalid("Usage: /rename <name>".to_string()),
+            "admin" => match arg.split_once(' ') {
+                Some((token, command)) if !command.trim().is_empty() => InputCommand::Admin {
+                    token: token.to_string(),
+                    command: command.trim().to_string(),
+                },
+                _ => InputCommand::Invalid("Usage: /admin <token> <command>".to_string()),
+            },
+            "rich" => match arg.split_once(' ') {
+                Some((content_type, json)) => match serde_json::from_str(json) {
+                    Ok(data) => InputCommand::Rich {
+                        content_type: content_type.to_string(),
+                        data,
+                    },
+                    Err(e) => InputCommand::Invalid(format!("Invalid JSON payload: {}", e)),
+                },
+                None => InputCommand::Invalid("Usage: /rich <content-type> <json>".to_string()),
+            },
+            "rotate-key" => InputCommand::RotateKey,
+            "help" => InputCommand::Help,
+            "quit" => InputCommand::Quit,
+            _ => InputCommand::Invalid(format!("Unknown command '/{}'. Type /help for a list of commands", command)),
+        };
+    }
+
+    InputCommand::Chat(line.to_string())
+}
+
+// Variants are named after the error they wrap
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 enum NoiseError {
     HandshakeError(String),
     EncryptionError(String),
     DecryptionError(String),
+    SerializationError(String),
 }
 
 impl std::fmt::Display for NoiseError {
@@ -30,23 +620,83 @@ impl std::fmt::Display for NoiseError {
             NoiseError::HandshakeError(msg) => write!(f, "Handshake error: {}", msg),
             NoiseError::EncryptionError(msg) => write!(f, "Encryption error: {}", msg),
             NoiseError::DecryptionError(msg) => write!(f, "Decryption error: {}", msg),
+            NoiseError::SerializationError(msg) => write!(f, "Serialization error: {}", msg),
         }
     }
 }
 
 impl Error for NoiseError {}
 
+// Each side names SUBPROTOCOL in its encrypted handshake payload, binding the
+// protocol version to the session: a man in the middle who rewrites the
+// (unauthenticated) WebSocket header to force a downgrade breaks the handshake.
+fn check_bound_protocol(payload: &[u8]) -> Result<String, NoiseError> {
+    if payload == SUBPROTOCOL.as_bytes() {
+        Ok(SUBPROTOCOL.to_string())
+    } else {
+        Err(NoiseError::HandshakeError(format!(
+            "peer's handshake names protocol '{}', expected '{}'",
+            String::from_utf8_lossy(payload),
+            SUBPROTOCOL
+        )))
+    }
+}
+
+/// What a completed handshake actually negotiated, alongside the session itself
+struct HandshakeOutcome {
+    session: NoiseSession,
+    pattern: String,
+    cipher: String,
+    psk_source: &'static str,
+    version: String,
+    remote_static_fp: Option<String>,
+}
+
 struct NoiseSession {
     transport: TransportState,
+    handshake_hash: Vec<u8>,
 }
 
 impl NoiseSession {
-    fn new(transport: TransportState) -> Self {
-        Self { transport }
+    fn new(transport: TransportState, handshake_hash: Vec<u8>) -> Self {
+        Self {
+            transport,
+            handshake_hash,
+        }
+    }
+
+    /// Short authentication string for comparing out of band. Both ends derive
+    /// it from the handshake hash, so a man in the middle can't make them match.
+    fn sas(&self) -> String {
+        let digest = Sha256::new()
+            .chain_update(b"secure-websocket sas")
+            .chain_update(&self.handshake_hash)
+            .finalize();
+        let code = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % 1_000_000;
+        format!("{:03} {:03}", code / 1000, code % 1000)
+    }
+
+    fn remote_static(&self) -> Option<&[u8]> {
+        self.transport.get_remote_static()
+    }
+
+    fn sending_nonce(&self) -> u64 {
+        self.transport.sending_nonce()
+    }
+
+    fn receiving_nonce(&self) -> u64 {
+        self.transport.receiving_nonce()
+    }
+
+    /// Serializes a chat message and encrypts it for sending. A message that
+    /// fails to serialize uses no nonce, so the session stays in step.
+    fn encrypt_message<T: Serialize>(&mut self, msg: &T) -> Result<Vec<u8>, NoiseError> {
+        let json = serde_json::to_vec(msg).map_err(|e| NoiseError::SerializationError(e.to_string()))?;
+        self.encrypt(&json)
     }
 
     fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, NoiseError> {
-        let mut ciphertext = vec![0u8; plaintext.len() + 16];
+        let mut ciphertext = vec![0u8; plaintext.len() + TAG_LEN];
         let len = self
             .transport
             .write_message(plaintext, &mut ciphertext)
@@ -64,12 +714,288 @@ impl NoiseSession {
         plaintext.truncate(len);
         Ok(plaintext)
     }
+
+    /// Describes a failed decrypt for the logs. Never includes plaintext or key material.
+    fn decrypt_failure_diagnostics(&self, ciphertext: &[u8]) -> String {
+        let hypothesis = if ciphertext.len() < TAG_LEN {
+            "frame shorter than the AEAD tag, likely truncated"
+        } else if ciphertext.len() > NOISE_MAX_MESSAGE_LEN {
+            "frame larger than any Noise message, likely not Noise ciphertext"
+        } else {
+            "authentication failed, likely cipher desync or wrong key; reconnect to rekey"
+        };
+        format!(
+            "ciphertext_len={} expected_nonce={} sent_nonce={} hypothesis=\"{}\"",
+            ciphertext.len(),
+            self.transport.receiving_nonce(),
+            self.transport.sending_nonce(),
+            hypothesis
+        )
+    }
+}
+
+#[derive(Clone)]
+struct StaticKeypair {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl StaticKeypair {
+    fn generate() -> Result<Self, NoiseError> {
+        let builder = Builder::new(noise_params()?);
+        let keypair = builder.generate_keypair().map_err(|e| NoiseError::HandshakeError(e.to_string()))?;
+        Ok(Self {
+            private: keypair.private,
+            public: keypair.public,
+        })
+    }
+
+    /// Loads the keypair stored at `path`, creating one if the file doesn't exist yet.
+    fn load_or_generate(path: &str) -> Result<Self, Box<dyn Error>> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                let mut lines = contents.lines();
+                let private = lines.next().and_then(from_hex);
+                let public = lines.next().and_then(from_hex);
+                match (private, public) {
+                    (Some(private), Some(public)) if private.len() == 32 && public.len() == 32 => {
+                        Ok(Self { private, public })
+                    }
+                    _ => Err(format!("Invalid static key file: {}", path).into()),
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::rotate(path),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Generates a new keypair and atomically replaces the one stored at `path`.
+    fn rotate(path: &str) -> Result<Self, Box<dyn Error>> {
+        let keypair = Self::generate()?;
+        let tmp_path = format!("{}.tmp", path);
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&tmp_path)?;
+        writeln!(file, "{}", to_hex(&keypair.private))?;
+        writeln!(file, "{}", to_hex(&keypair.public))?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(keypair)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Short, non-reversible identifier for secret material, safe to display
+fn fingerprint(secret: &[u8]) -> String {
+    let digest = Sha256::new()
+        .chain_update(b"secure-websocket fingerprint")
+        .chain_update(secret)
+        .finalize();
+    to_hex(&digest[..8])
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim();
+    if !s.is_ascii() || !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn noise_pattern() -> String {
+    match NOISE_PATTERN_OVERRIDE {
+        Some(pattern) => pattern.to_string(),
+        None => format!("Noise_{}_{}_{}_{}", HANDSHAKE_PATTERN, DH_FUNCTION, CIPHER, HASH),
+    }
+}
+
+/// Parses the configured protocol, naming the offending component when one
+/// isn't something snow (or this handshake code) supports.
+fn noise_params() -> Result<NoiseParams, NoiseError> {
+    parse_noise_params(&noise_pattern())
+}
+
+fn parse_noise_params(pattern: &str) -> Result<NoiseParams, NoiseError> {
+    let parts: Vec<&str> = pattern.split('_').collect();
+    let [_, handshake, dh, cipher, hash] = parts[..] else {
+        return Err(NoiseError::HandshakeError(format!(
+            "Noise protocol {} is not of the form Noise_<pattern>_<dh>_<cipher>_<hash>",
+            pattern
+        )));
+    };
+    let checks = [
+        ("handshake pattern", handshake, &["XXpsk2"][..]),
+        ("DH function", dh, &["25519"][..]),
+        ("cipher", cipher, &["AESGCM", "ChaChaPoly"][..]),
+        ("hash", hash, &["SHA256", "SHA512", "BLAKE2s", "BLAKE2b"][..]),
+    ];
+    for (component, value, supported) in checks {
+        if !supported.contains(&value) {
+            return Err(NoiseError::HandshakeError(format!(
+                "Unsupported {} '{}' in {}; supported: {}",
+                component,
+                value,
+                pattern,
+                supported.join(", ")
+            )));
+        }
+    }
+    pattern
+        .parse()
+        .map_err(|e| NoiseError::HandshakeError(format!("Invalid Noise protocol {}: {}", pattern, e)))
+}
+
+/// Opens a TCP connection to host:port through the SOCKS5 proxy (RFC 1928),
+/// authenticating with SOCKS5_AUTH (RFC 1929) if set.
+async fn socks5_connect(proxy: &str, host: &str, port: u16) -> io::Result<TcpStream> {
+    let fail = |msg: String| io::Error::other(msg);
+    let mut stream = TcpStream::connect(proxy).await?;
+
+    // Offer no-auth, or username/password only when credentials are configured
+    let method = if SOCKS5_AUTH.is_some() { 0x02 } else { 0x00 };
+    stream.write_all(&[0x05, 0x01, method]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 0x05 || reply[1] != method {
+        return Err(fail("proxy refused the offered authentication method".to_string()));
+    }
+
+    if let Some((user, password)) = SOCKS5_AUTH {
+        if user.len() > 255 || password.len() > 255 {
+            return Err(fail("username and password must be at most 255 bytes".to_string()));
+        }
+        let mut auth = vec![0x01, user.len() as u8];
+        auth.extend_from_slice(user.as_bytes());
+        auth.push(password.len() as u8);
+        auth.extend_from_slice(password.as_bytes());
+        stream.write_all(&auth).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err(fail("authentication failed".to_string()));
+        }
+    }
+
+    // CONNECT by IP literal, or by name so the proxy does the DNS lookup
+    let mut request = vec![0x05, 0x01, 0x00];
+    match host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(ip)) => {
+            request.push(0x01);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(std::net::IpAddr::V6(ip)) => {
+            request.push(0x04);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(fail("host name is longer than 255 bytes".to_string()));
+            }
+            request.push(0x03);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[1] != 0x00 {
+        let reason = match header[1] {
+            0x01 => "general failure",
+            0x02 => "connection not allowed by ruleset",
+            0x03 => "network unreachable",
+            0x04 => "host unreachable",
+            0x05 => "connection refused",
+            0x06 => "TTL expired",
+            0x07 => "command not supported",
+            0x08 => "address type not supported",
+            _ => "unknown error",
+        };
+        return Err(fail(format!("could not reach {}:{}: {}", host, port, reason)));
+    }
+    // Skip the bound address the proxy reports
+    let address_len = match header[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        _ => return Err(fail("malformed reply".to_string())),
+    };
+    let mut bound = vec![0u8; address_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(stream)
+}
+
+/// Applies TCP_NODELAY, TCP_KEEPALIVE_SECS and SOCKET_BUFFER_SIZE to a connection.
+fn tune_socket(stream: &TcpStream) -> io::Result<()> {
+    stream.set_nodelay(TCP_NODELAY)?;
+    let socket = SockRef::from(stream);
+    if let Some(secs) = TCP_KEEPALIVE_SECS {
+        socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(Duration::from_secs(secs)))?;
+    }
+    if let Some(size) = SOCKET_BUFFER_SIZE {
+        socket.set_recv_buffer_size(size)?;
+        socket.set_send_buffer_size(size)?;
+    }
+    Ok(())
+}
+
+/// Upper bound on any handshake message for the Noise protocol: an ephemeral key,
+/// an encrypted static key and an encrypted payload. Errors if that can't fit
+/// in a Noise message, so a bad pattern fails at startup rather than mid-handshake.
+fn handshake_buffer_len() -> Result<usize, NoiseError> {
+    handshake_buffer_len_for(&noise_pattern(), MAX_HANDSHAKE_PAYLOAD_LEN)
+}
+
+fn handshake_buffer_len_for(pattern: &str, max_payload_len: usize) -> Result<usize, NoiseError> {
+    // noise_params accepts no other curve
+    let dh_len = match pattern.split('_').nth(2) {
+        Some("25519") => 32,
+        other => {
+            return Err(NoiseError::HandshakeError(format!(
+                "Unsupported DH function {:?} in {}",
+                other, pattern
+            )))
+        }
+    };
+    if SUBPROTOCOL.len() > max_payload_len {
+        return Err(NoiseError::HandshakeError(format!(
+            "Subprotocol name {} is longer than the {}-byte handshake payload limit",
+            SUBPROTOCOL, max_payload_len
+        )));
+    }
+    let len = dh_len + (dh_len + TAG_LEN) + (max_payload_len + TAG_LEN);
+    if len > NOISE_MAX_MESSAGE_LEN {
+        return Err(NoiseError::HandshakeError(format!(
+            "Handshake messages for {} can reach {} bytes, over the Noise limit of {}",
+            pattern, len, NOISE_MAX_MESSAGE_LEN
+        )));
+    }
+    Ok(len)
 }
 
-fn create_initiator() -> Result<HandshakeState, NoiseError> {
-    let builder = Builder::new(NOISE_PATTERN.parse().unwrap());
-    let keypair = builder.generate_keypair().map_err(|e| NoiseError::HandshakeError(e.to_string()))?;
-    
+fn create_initiator(static_key: Option<&StaticKeypair>) -> Result<HandshakeState, NoiseError> {
+    let builder = Builder::new(noise_params()?);
+    let keypair = match static_key {
+        Some(keypair) => keypair.clone(),
+        None => StaticKeypair::generate()?,
+    };
+
     builder
         .local_private_key(&keypair.private)
         .psk(2, PSK)
@@ -79,130 +1005,1140 @@ fn create_initiator() -> Result<HandshakeState, NoiseError> {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let url = "ws://127.0.0.1:8080";
+    let mut url = "ws://127.0.0.1:8080".to_string();
+    noise_params()?;
+    handshake_buffer_len()?;
+
+    let static_key = match STATIC_KEY_FILE {
+        Some(path) => {
+            let keypair = StaticKeypair::load_or_generate(path)?;
+            println!("Static public key: {}", to_hex(&keypair.public));
+            Some(keypair)
+        }
+        None => None,
+    };
+
+    let message_log = match MESSAGE_LOG_FILE {
+        Some(path) => {
+            let log = MessageLog::open(path)?;
+            println!("Logging messages in plaintext to: {}", path);
+            Some(Arc::new(std::sync::Mutex::new(log)))
+        }
+        None => None,
+    };
+
+    // Stdin outlives any one connection, so a redirect doesn't lose typed lines
+    let (input_tx, input_rx) = mpsc::unbounded_channel();
+    // Only redraw the prompt when a person is watching; piped output stays plain
+    let interactive = io::stdout().is_terminal();
+    let _terminal = TerminalGuard;
+    #[cfg(unix)]
+    let input_tx = if interactive && io::stdin().is_terminal() {
+        match start_line_editor(input_tx.clone()) {
+            Ok(()) => None,
+            Err(e) => {
+                eprintln!("Failed to set up the terminal, typed text won't be redrawn: {}", e);
+                Some(input_tx)
+            }
+        }
+    } else {
+        Some(input_tx)
+    };
+    #[cfg(not(unix))]
+    let input_tx = Some(input_tx);
+    if let Some(input_tx) = input_tx {
+        tokio::spawn(forward_lines(BufReader::new(tokio::io::stdin()), input_tx));
+    }
+    let input_rx = Arc::new(Mutex::new(input_rx));
+
+    let session = Session {
+        static_key: Arc::new(std::sync::Mutex::new(static_key)),
+        message_log,
+        input_rx,
+        // An observer has no name of its own, so the prompt is answered for it
+        name: Arc::new(Mutex::new(OBSERVER_TOKEN.map(|_| "observer".to_string()))),
+        interactive,
+    };
+    let mut reconnects = Reconnects::default();
+    loop {
+        let started = tokio::time::Instant::now();
+        match run_session(&url, &session).await {
+            Ok(SessionEnd::Closed) => break,
+            Ok(SessionEnd::Redirect(next)) => {
+                println!("Server moved to {}, reconnecting...", next);
+                url = next;
+            }
+            Ok(SessionEnd::Reconnect(reason)) => {
+                let Some(delay) = reconnects.next_delay(Some(started.elapsed())) else {
+                    if RECONNECT_ATTEMPTS > 0 {
+                        eprintln!("Giving up after {} reconnect attempts", RECONNECT_ATTEMPTS);
+                    }
+                    break;
+                };
+                // The same name would only be rejected again, so ask for a new one
+                if matches!(reason, DisconnectReason::InvalidName) {
+                    *session.name.lock().await = None;
+                }
+                println!("Reconnecting in {}s...", delay.as_secs());
+                tokio::time::sleep(delay).await;
+            }
+            // Only a reconnect is retried; failing to reach the server at startup exits
+            Err(e) if reconnects.attempts > 0 => match reconnects.next_delay(None) {
+                Some(delay) => {
+                    eprintln!("Reconnect failed: {}; retrying in {}s...", e, delay.as_secs());
+                    tokio::time::sleep(delay).await;
+                }
+                None => return Err(e),
+            },
+            Err(e) => return Err(e),
+        }
+    }
+
+    println!("Disconnected");
+    Ok(())
+}
+
+// Why forward_lines stopped
+#[derive(Debug, PartialEq)]
+enum InputEnd {
+    // Input reached end of file, e.g. a piped file ran out
+    Eof,
+    // Reading input failed
+    Failed,
+    // The client is shutting down and no longer reads lines
+    Closed,
+}
+
+/// Sends each line of `input` to the session. Dropping input_tx when input
+/// ends or fails tells the session to disconnect.
+async fn forward_lines<R: AsyncBufRead + Unpin>(input: R, input_tx: mpsc::UnboundedSender<String>) -> InputEnd {
+    let mut lines = input.lines();
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) => {
+                if input_tx.send(line).is_err() {
+                    return InputEnd::Closed;
+                }
+            }
+            Ok(None) => return InputEnd::Eof,
+            Err(e) => {
+                eprintln!("Failed to read input: {}", e);
+                return InputEnd::Failed;
+            }
+        }
+    }
+}
+
+/// Waits for a non-empty line to answer the name prompt with. None if input
+/// ends or the user quits instead.
+async fn read_name(input_rx: &Mutex<mpsc::UnboundedReceiver<String>>) -> Option<String> {
+    let mut input_rx = input_rx.lock().await;
+    while let Some(line) = input_rx.recv().await {
+        match parse_input(line.trim()) {
+            InputCommand::Quit => return None,
+            _ if line.trim().is_empty() => print_prompt(),
+            _ => return Some(line.trim().to_string()),
+        }
+    }
+    None
+}
+
+// Reconnect attempts since the last session that stayed up, so a server that
+// keeps sending a reconnectable reason can't hold the client in a loop
+#[derive(Default)]
+struct Reconnects {
+    attempts: u32,
+}
+
+impl Reconnects {
+    // The wait before the next attempt, or None once RECONNECT_ATTEMPTS are
+    // used up. `session_lasted` is how long the session that just ended was
+    // up; None means the attempt failed before a session started
+    fn next_delay(&mut self, session_lasted: Option<Duration>) -> Option<Duration> {
+        if session_lasted.is_some_and(|d| d >= Duration::from_secs(RECONNECT_STABLE_SECS)) {
+            self.attempts = 0;
+        }
+        if self.attempts >= RECONNECT_ATTEMPTS {
+            return None;
+        }
+        self.attempts += 1;
+        // Capped so a large RECONNECT_ATTEMPTS can't overflow the shift
+        Some(Duration::from_secs(RECONNECT_DELAY_SECS << (self.attempts - 1).min(6)))
+    }
+}
+
+// State that carries over when the server redirects the client elsewhere
+struct Session {
+    // Behind a lock so /rotate-key applies to the next reconnect or redirect
+    static_key: Arc<std::sync::Mutex<Option<StaticKeypair>>>,
+    message_log: Option<Arc<std::sync::Mutex<MessageLog>>>,
+    input_rx: Arc<Mutex<mpsc::UnboundedReceiver<String>>>,
+    // Set from the first line sent, which the server takes as the name
+    name: Arc<Mutex<Option<String>>>,
+    interactive: bool,
+}
+
+enum SessionEnd {
+    Closed,
+    Redirect(String),
+    // The server dropped the client but said reconnecting is worthwhile
+    Reconnect(DisconnectReason),
+}
+
+/// Connects to `url` and chats until the connection ends or the server
+/// redirects the client. After a redirect the name is sent automatically.
+async fn run_session(url: &str, session: &Session) -> Result<SessionEnd, Box<dyn std::error::Error>> {
     println!("Connecting to server at: {}", url);
+    let mut request = url.into_client_request()?;
+    request
+        .headers_mut()
+        .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(SUBPROTOCOL));
+    // Lets the server name a Noise protocol mismatch before the handshake starts
+    request
+        .headers_mut()
+        .insert(NOISE_PROTOCOL_HEADER, HeaderValue::from_str(&noise_pattern())?);
 
-    let (ws_stream, _) = connect_async(url).await?;
+    // Tungstenite reassembles continuation frames, so each Binary read is one
+    // complete Noise message; anything larger than Noise allows is rejected early
+    let config = WebSocketConfig {
+        max_message_size: Some(NOISE_MAX_MESSAGE_LEN),
+        max_frame_size: Some(NOISE_MAX_MESSAGE_LEN),
+        ..Default::default()
+    };
+    // Connect the socket ourselves so its options can be set before the upgrade
+    let uri = request.uri();
+    let host = uri.host().ok_or("Server URL has no host")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+    let port = uri
+        .port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("wss") { 443 } else { 80 });
+    let stream = match SOCKS5_PROXY {
+        Some(proxy) => {
+            println!("Connecting through SOCKS5 proxy {}", proxy);
+            socks5_connect(proxy, &host, port)
+                .await
+                .map_err(|e| format!("SOCKS5 proxy {}: {}", proxy, e))?
+        }
+        None => TcpStream::connect((host.as_str(), port)).await?,
+    };
+    if let Err(e) = tune_socket(&stream) {
+        eprintln!("Failed to set socket options: {}", e);
+    }
+    let (ws_stream, response) = match client_async_with_config(request, MaybeTlsStream::Plain(stream), Some(config)).await {
+        Ok(connected) => connected,
+        // A refused upgrade explains itself in the body, e.g. a Noise protocol mismatch
+        Err(WsError::Http(response)) => {
+            let body = response.body().as_deref().map(String::from_utf8_lossy).unwrap_or_default();
+            return Err(format!("Server refused the connection ({}): {}", response.status(), body).into());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let accepted = response
+        .headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok());
+    if accepted != Some(SUBPROTOCOL) {
+        return Err(format!("Server did not accept subprotocol {}", SUBPROTOCOL).into());
+    }
     println!("Connected to server");
     println!("Starting Noise handshake...");
 
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
 
-    let noise_session = match perform_noise_handshake_initiator(&mut ws_sender, &mut ws_receiver).await {
-        Ok(session) => session,
+    let static_key = session.static_key.lock().unwrap().clone();
+    let outcome = match perform_noise_handshake_initiator(&mut ws_sender, &mut ws_receiver, static_key.as_ref()).await {
+        Ok(outcome) => outcome,
         Err(e) => {
             eprintln!("Noise handshake failed: {}", e);
-            return Ok(());
+            // Lets the server tell a rejected handshake from a dropped connection
+            let _ = ws_sender.send(Message::Close(None)).await;
+            return Ok(SessionEnd::Closed);
         }
     };
 
-    println!("Secure channel established");
+    let HandshakeOutcome {
+        session: mut noise_session,
+        pattern,
+        cipher,
+        psk_source,
+        version,
+        remote_static_fp,
+    } = outcome;
 
+    println!("Secure channel established ({}, {})", pattern, version);
+    if let Some(remote) = noise_session.remote_static() {
+        println!("Server static key: {}", to_hex(remote));
+    }
+
+    // The server asks for a name first. Rejoining after a redirect, or observing,
+    // answers with the name already known without showing the prompt; otherwise
+    // the next line typed is the reply, and becomes this session's name
+    let prompt = match ws_receiver.next().await {
+        Some(Ok(Message::Binary(prompt))) => noise_session.decrypt(&prompt)?,
+        _ => {
+            print_final("Server disconnected", session.interactive);
+            return Ok(SessionEnd::Closed);
+        }
+    };
+    let known_name = session.name.lock().await.clone();
+    let typed = known_name.is_none();
+    let name = match known_name {
+        Some(name) => name,
+        None => {
+            if let Ok(prompt) = serde_json::from_slice::<ChatMessage>(&prompt) {
+                log_message(&session.message_log, "in", &prompt);
+                print_incoming(&format!("{}: {}", prompt.sender, prompt.content), session.interactive);
+            }
+            print_prompt();
+            match read_name(&session.input_rx).await {
+                Some(name) => name,
+                None => {
+                    println!("Disconnecting...");
+                    let _ = ws_sender.send(Message::Close(None)).await;
+                    return Ok(SessionEnd::Closed);
+                }
+            }
+        }
+    };
+    let reply = ChatMessage {
+        sender: String::new(),
+        content: name.clone(),
+        control: OBSERVER_TOKEN.map(|token| ControlMessage::Observe { token: token.to_string() }),
+        rich: None,
+        meta: None,
+    };
+    let encrypted = noise_session.encrypt_message(&reply)?;
+    ws_sender.send(Message::Binary(encrypted)).await?;
+    if typed {
+        log_message(&session.message_log, "out", &reply);
+    }
+    *session.name.lock().await = Some(name);
+
+    let url = url.to_string();
+    let interactive = session.interactive;
+    let message_log = session.message_log.clone();
+    let message_log_incoming = session.message_log.clone();
+    let input_rx = Arc::clone(&session.input_rx);
+    let static_key_input = Arc::clone(&session.static_key);
+    let my_name_incoming = Arc::clone(&session.name);
     let noise_session = Arc::new(Mutex::new(noise_session));
     let noise_session_clone = Arc::clone(&noise_session);
+    let roster = Arc::new(Mutex::new(Roster::default()));
+    let roster_incoming = Arc::clone(&roster);
+    let pending_echoes = Arc::new(std::sync::Mutex::new(PendingEchoes::default()));
+    let pending_echoes_incoming = Arc::clone(&pending_echoes);
 
     // Handle incoming messages
-    let incoming_task = tokio::spawn(async move {
+    let mut incoming_task = tokio::spawn(async move {
         while let Some(msg) = ws_receiver.next().await {
             match msg {
                 Ok(Message::Binary(encrypted_data)) => {
                     let mut session = noise_session_clone.lock().await;
                     match session.decrypt(&encrypted_data) {
                         Ok(decrypted) => {
-                            if let Ok(json_str) = String::from_utf8(decrypted) {
-                                if let Ok(chat_msg) = serde_json::from_str::<ChatMessage>(&json_str) {
-                                    println!("{}: {}", chat_msg.sender, chat_msg.content);
+                            for part in unpack_batch(decrypted) {
+                                if let Ok(json_str) = String::from_utf8(part) {
+                                    if let Ok(chat_msg) = serde_json::from_str::<ChatMessage>(&json_str) {
+                                        if logs_incoming(&chat_msg, LOG_SYSTEM_NOTICES) {
+                                            log_message(&message_log_incoming, "in", &chat_msg);
+                                        }
+                                        match chat_msg.control {
+                                            Some(ControlMessage::Direct { to }) => {
+                                                // An observer sees private messages between others
+                                                let line = if OBSERVER_TOKEN.is_some() {
+                                                    format!("{} -> {} (private): {}", chat_msg.sender, to, chat_msg.content)
+                                                } else {
+                                                    format!("{} (private): {}", chat_msg.sender, chat_msg.content)
+                                                };
+                                                print_incoming(&line, interactive);
+                                            }
+                                            Some(ControlMessage::Disconnect { reason, reconnect }) => {
+                                                print_final(&format!("{} [{}]", chat_msg.content, reason.as_str()), interactive);
+                                                if reconnect {
+                                                    return SessionEnd::Reconnect(reason);
+                                                }
+                                                println!("Reconnecting right away will not help");
+                                                return SessionEnd::Closed;
+                                            }
+                                            Some(ControlMessage::System) => {
+                                                print_incoming(&system_notice_line(&chat_msg), interactive);
+                                            }
+                                            Some(ControlMessage::Redirect { url }) => {
+                                                print_final(&chat_msg.content, interactive);
+                                                return SessionEnd::Redirect(url);
+                                            }
+                                            Some(ControlMessage::Unknown) => match unknown_control_line(&chat_msg, REJECT_UNKNOWN_CONTROL) {
+                                                Some(line) => print_incoming(&line, interactive),
+                                                None => {
+                                                    if DEBUG_LOGGING {
+                                                        eprintln!("Dropped a message of a type this client does not support");
+                                                    }
+                                                }
+                                            },
+                                            Some(control) => {
+                                                if let ControlMessage::RosterRename { old, new, .. } = &control {
+                                                    let mut my_name = my_name_incoming.lock().await;
+                                                    if my_name.as_deref() == Some(old.as_str()) {
+                                                        *my_name = Some(new.clone());
+                                                    }
+                                                }
+                                                if roster_incoming.lock().await.apply(control) {
+                                                    print_incoming(&format!("{}: {}", chat_msg.sender, chat_msg.content), interactive);
+                                                }
+                                            }
+                                            None => {
+                                                let my_name = my_name_incoming.lock().await.clone();
+                                                let echo = pending_echoes_incoming.lock().unwrap().reconcile(&chat_msg, my_name.as_deref());
+                                                match echo {
+                                                    // Already on screen as typed
+                                                    Echo::Confirmed(id) => {
+                                                        if DEBUG_LOGGING {
+                                                            eprintln!("Server confirmed message #{}", id);
+                                                        }
+                                                    }
+                                                    Echo::Altered(id) => print_incoming(
+                                                        &format!("Your message was relayed as #{}: {}", id, chat_msg.content),
+                                                        interactive,
+                                                    ),
+                                                    Echo::NotOwn => match chat_msg.rich.as_ref().and_then(RichPayload::render) {
+                                                        Some(rendered) => print_incoming(&format!("{}: {}", chat_msg.sender, rendered), interactive),
+                                                        None => print_incoming(&format!("{}: {}", chat_msg.sender, chat_msg.content), interactive),
+                                                    },
+                                                }
+                                            }
+                                        }
+                                    }
                                 }
                             }
                         }
                         Err(e) => {
-                            eprintln!("Decryption failed: {}", e);
+                            eprintln!("Decryption failed for server {}: {}", url, e);
+                            if DEBUG_LOGGING {
+                                eprintln!("  {}", session.decrypt_failure_diagnostics(&encrypted_data));
+                            }
                         }
                     }
                 }
                 Ok(Message::Close(_)) => {
-                    println!("Server disconnected");
+                    print_final("Server disconnected", interactive);
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("WebSocket error: {}", e);
                     break;
                 }
                 _ => {}
             }
         }
+        SessionEnd::Closed
     });
 
     // Handle user input
-    let input_task = tokio::spawn(async move {
-        let stdin = tokio::io::stdin();
-        let reader = BufReader::new(stdin);
-        let mut lines = reader.lines();
+    let mut input_task = tokio::spawn(async move {
+        let mut input_rx = input_rx.lock().await;
 
-        print!("> ");
-        io::stdout().flush().unwrap();
+        print_prompt();
 
-        while let Ok(Some(line)) = lines.next_line().await {
+        while let Some(line) = input_rx.recv().await {
             let line = line.trim();
 
             if line.is_empty() {
-                print!("> ");
-                io::stdout().flush().unwrap();
+                print_prompt();
                 continue;
             }
 
-            if line.eq_ignore_ascii_case("quit") {
-                println!("Disconnecting...");
-                let _ = ws_sender.send(Message::Close(None)).await;
-                break;
-            }
-
-            let chat_msg = ChatMessage {
-                sender: String::new(),
-                content: line.to_string(),
+            let chat_msg = match parse_input(line) {
+                InputCommand::Quit => {
+                    println!("Disconnecting...");
+                    let _ = ws_sender.send(Message::Close(None)).await;
+                    return;
+                }
+                InputCommand::Help => {
+                    println!("{}", HELP);
+                    None
+                }
+                InputCommand::Who => {
+                    let roster = roster.lock().await;
+                    println!("Online ({}): {}", roster.names.len(), roster.names.join(", "));
+                    None
+                }
+                InputCommand::SecInfo => {
+                    let session = noise_session.lock().await;
+                    println!("Noise pattern:     {}", pattern);
+                    println!("Cipher:            {}", cipher);
+                    println!("Protocol version:  {}", version);
+                    println!("PSK source:        {}", psk_source);
+                    println!("PSK fingerprint:   {}", fingerprint(PSK));
+                    match (session.remote_static(), &remote_static_fp) {
+                        (Some(remote), Some(fp)) => println!("Server static key: {} (fingerprint {})", to_hex(remote), fp),
+                        _ => println!("Server static key: none"),
+                    }
+                    println!(
+                        "Nonces:            {} sent, {} received",
+                        session.sending_nonce(),
+                        session.receiving_nonce()
+                    );
+                    None
+                }
+                InputCommand::Verify => {
+                    println!("Verification code: {}", noise_session.lock().await.sas());
+                    println!("Compare it with the server operator over a channel you trust; a match means no one is in the middle");
+                    None
+                }
+                InputCommand::RotateKey => {
+                    match STATIC_KEY_FILE {
+                        Some(path) => match StaticKeypair::rotate(path) {
+                            Ok(keypair) => {
+                                println!(
+                                    "Static key rotated, used from the next connection. New public key: {}",
+                                    to_hex(&keypair.public)
+                                );
+                                *static_key_input.lock().unwrap() = Some(keypair);
+                            }
+                            Err(e) => println!("Failed to rotate static key: {}", e),
+                        },
+                        None => println!("No persistent static key configured (STATIC_KEY_FILE)"),
+                    }
+                    None
+                }
+                InputCommand::Invalid(reason) => {
+                    println!("{}", reason);
+                    None
+                }
+                InputCommand::Rename(name) => Some(ChatMessage {
+                    sender: String::new(),
+                    content: String::new(),
+                    control: Some(ControlMessage::Rename { name }),
+                    rich: None,
+                    meta: None,
+                }),
+                InputCommand::Admin { token, command } => Some(ChatMessage {
+                    sender: String::new(),
+                    content: String::new(),
+                    control: Some(ControlMessage::Admin { token, command }),
+                    rich: None,
+                    meta: None,
+                }),
+                InputCommand::Direct { to, content } => Some(ChatMessage {
+                    sender: String::new(),
+                    content,
+                    control: Some(ControlMessage::Direct { to }),
+                    rich: None,
+                    meta: None,
+                }),
+                InputCommand::Chat(content) => Some(ChatMessage {
+                    sender: String::new(),
+                    content,
+                    control: None,
+                    rich: None,
+                    meta: None,
+                }),
+                InputCommand::Rich { content_type, data } => Some(ChatMessage {
+                    sender: String::new(),
+                    content: format!("[{} payload]", content_type),
+                    control: None,
+                    rich: Some(RichPayload { content_type, data }),
+                    meta: None,
+                }),
             };
 
-            if let Ok(json) = serde_json::to_string(&chat_msg) {
-                let mut session = noise_session.lock().await;
-                if let Ok(encrypted) = session.encrypt(json.as_bytes()) {
-                    if ws_sender.send(Message::Binary(encrypted)).await.is_err() {
-                        break;
+            if let Some(chat_msg) = chat_msg {
+                let encrypted = noise_session.lock().await.encrypt_message(&chat_msg);
+                match encrypted {
+                    Ok(encrypted) => {
+                        if ws_sender.send(Message::Binary(encrypted)).await.is_err() {
+                            return;
+                        }
+                        // Admin requests carry the token, which has no place in the log
+                        if !matches!(chat_msg.control, Some(ControlMessage::Admin { .. })) {
+                            log_message(&message_log, "out", &chat_msg);
+                        }
+                        if chat_msg.control.is_none() {
+                            pending_echoes.lock().unwrap().sent(&chat_msg.content);
+                        }
                     }
+                    Err(e) => println!("Message could not be sent: {}", e),
                 }
             }
 
-            print!("> ");
-            io::stdout().flush().unwrap();
+            print_prompt();
         }
-    });
 
-    tokio::select! {
-        _ = incoming_task => {}
-        _ = input_task => {}
-    }
+        // Input ended (e.g. a piped file ran out): leave as if the user quit
+        println!("\nEnd of input, disconnecting...");
+        let _ = ws_sender.send(Message::Close(None)).await;
+    });
 
-    println!("Disconnected");
-    Ok(())
+    let end = tokio::select! {
+        end = &mut incoming_task => end.unwrap_or(SessionEnd::Closed),
+        _ = &mut input_task => SessionEnd::Closed,
+    };
+    incoming_task.abort();
+    input_task.abort();
+    Ok(end)
 }
 
 async fn perform_noise_handshake_initiator(
     ws_sender: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>, Message>,
     ws_receiver: &mut futures_util::stream::SplitStream<tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>>,
-) -> Result<NoiseSession, Box<dyn std::error::Error>> {
-    let mut handshake = create_initiator()?;
-    let mut buf = vec![0u8; 65535];
+    static_key: Option<&StaticKeypair>,
+) -> Result<HandshakeOutcome, Box<dyn std::error::Error>> {
+    let mut handshake = create_initiator(static_key)?;
+    let mut buf = vec![0u8; handshake_buffer_len()?];
 
+    // The protocol name already went in the upgrade request header
     let len = handshake.write_message(&[], &mut buf)?;
     ws_sender.send(Message::Binary(buf[..len].to_vec())).await?;
 
     if let Some(msg) = ws_receiver.next().await {
         match msg? {
             Message::Binary(data) => {
-                handshake.read_message(&data, &mut buf)?;
-                let len = handshake.write_message(&[], &mut buf)?;
+                // The server has already accepted our protocol name at the upgrade,
+                // so a reply that fails to decrypt points at the one secret not advertised
+                let len = handshake.read_message(&data, &mut buf).map_err(|e| match e {
+                    snow::Error::Decrypt => NoiseError::HandshakeError(
+                        "the server's reply failed to decrypt; the PSK differs from the server's".to_string(),
+                    ),
+                    e => NoiseError::HandshakeError(e.to_string()),
+                })?;
+                let version = check_bound_protocol(&buf[..len])?;
+                let len = handshake.write_message(SUBPROTOCOL.as_bytes(), &mut buf)?;
                 ws_sender.send(Message::Binary(buf[..len].to_vec())).await?;
+                let handshake_hash = handshake.get_handshake_hash().to_vec();
                 let transport = handshake.into_transport_mode()?;
-                Ok(NoiseSession::new(transport))
+                let session = NoiseSession::new(transport, handshake_hash);
+                let pattern = noise_pattern();
+                Ok(HandshakeOutcome {
+                    cipher: pattern.split('_').nth(3).unwrap_or("unknown").to_string(),
+                    pattern,
+                    psk_source: "static (compiled in, not QKD)",
+                    version,
+                    remote_static_fp: session.remote_static().map(fingerprint),
+                    session,
+                })
             }
+            Message::Close(Some(frame)) => Err(format!("Server refused the handshake: {}", frame.reason).into()),
             _ => Err("Expected binary message".into()),
         }
     } else {
         Err("Connection closed".into())
     }
-} 
\ No newline at end of file
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_input_plain_chat() {
+        assert_eq!(parse_input("hello there"), InputCommand::Chat("hello there".to_string()));
+    }
+
+    #[test]
+    fn parse_input_slash_escape() {
+        assert_eq!(parse_input("//who"), InputCommand::Chat("/who".to_string()));
+        assert_eq!(parse_input("//"), InputCommand::Chat("/".to_string()));
+    }
+
+    #[test]
+    fn parse_input_quit() {
+        assert_eq!(parse_input("quit"), InputCommand::Quit);
+        assert_eq!(parse_input("QUIT"), InputCommand::Quit);
+        assert_eq!(parse_input("/quit"), InputCommand::Quit);
+    }
+
+    #[test]
+    fn parse_input_commands() {
+        assert_eq!(parse_input("/who"), InputCommand::Who);
+        assert_eq!(parse_input("/secinfo"), InputCommand::SecInfo);
+        assert_eq!(parse_input("/verify"), InputCommand::Verify);
+        assert_eq!(parse_input("/rotate-key"), InputCommand::RotateKey);
+        assert_eq!(parse_input("/help"), InputCommand::Help);
+        assert_eq!(parse_input("/WHO"), InputCommand::Who);
+        assert_eq!(parse_input("/rename Bob"), InputCommand::Rename("Bob".to_string()));
+        assert_eq!(
+            parse_input("/admin secret /list"),
+            InputCommand::Admin { token: "secret".to_string(), command: "/list".to_string() }
+        );
+        assert_eq!(
+            parse_input("/rich text/markdown {\"text\":\"*hi*\"}"),
+            InputCommand::Rich {
+                content_type: "text/markdown".to_string(),
+                data: serde_json::json!({ "text": "*hi*" }),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_input_direct() {
+        assert_eq!(
+            parse_input("@Alice see you"),
+            InputCommand::Direct { to: "Alice".to_string(), content: "see you".to_string() }
+        );
+    }
+
+    #[test]
+    fn parse_input_missing_arguments() {
+        assert!(matches!(parse_input("/rename"), InputCommand::Invalid(_)));
+        assert!(matches!(parse_input("/admin"), InputCommand::Invalid(_)));
+        assert!(matches!(parse_input("/admin secret"), InputCommand::Invalid(_)));
+        assert!(matches!(parse_input("/rich"), InputCommand::Invalid(_)));
+        assert!(matches!(parse_input("/rich text/plain {"), InputCommand::Invalid(_)));
+        assert!(matches!(parse_input("@Alice"), InputCommand::Invalid(_)));
+        assert!(matches!(parse_input("@ hi"), InputCommand::Invalid(_)));
+        assert!(matches!(parse_input("/nope"), InputCommand::Invalid(_)));
+    }
+
+    #[test]
+    fn parse_input_extra_whitespace() {
+        assert_eq!(parse_input("/rename   Bob  "), InputCommand::Rename("Bob".to_string()));
+        assert_eq!(
+            parse_input("/admin secret   /stats  "),
+            InputCommand::Admin { token: "secret".to_string(), command: "/stats".to_string() }
+        );
+        assert_eq!(
+            parse_input("@Alice   see you  "),
+            InputCommand::Direct { to: "Alice".to_string(), content: "see you".to_string() }
+        );
+        assert!(matches!(parse_input("/rename    "), InputCommand::Invalid(_)));
+    }
+
+    #[test]
+    fn unpack_batch_passes_a_plain_message_through() {
+        let message = b"{\"sender\":\"a\",\"content\":\"hi\"}".to_vec();
+        assert_eq!(unpack_batch(message.clone()), vec![message]);
+    }
+
+    #[test]
+    fn unpack_batch_splits_a_batch() {
+        let mut frame = vec![BATCH_MARKER];
+        for message in [&b"{}"[..], b"{\"n\":1}"] {
+            frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+            frame.extend_from_slice(message);
+        }
+        assert_eq!(unpack_batch(frame), vec![b"{}".to_vec(), b"{\"n\":1}".to_vec()]);
+    }
+
+    #[test]
+    fn unpack_batch_stops_at_a_truncated_entry() {
+        let mut frame = vec![BATCH_MARKER];
+        frame.extend_from_slice(&2u32.to_be_bytes());
+        frame.extend_from_slice(b"{}");
+        frame.extend_from_slice(&10u32.to_be_bytes());
+        frame.extend_from_slice(b"{\"n\"");
+        assert_eq!(unpack_batch(frame), vec![b"{}".to_vec()]);
+    }
+
+    fn snapshot(version: u64, names: &[&str]) -> ControlMessage {
+        ControlMessage::RosterSnapshot {
+            version,
+            names: names.iter().map(|n| n.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn roster_applies_updates_in_order() {
+        let mut roster = Roster::default();
+        assert!(roster.apply(snapshot(3, &["Bob"])));
+        assert!(roster.apply(ControlMessage::RosterJoin { version: 4, name: "Alice".to_string() }));
+        assert_eq!(roster.names, ["Alice", "Bob"]);
+        assert!(roster.apply(ControlMessage::RosterRename { version: 5, old: "Bob".to_string(), new: "Carol".to_string() }));
+        assert_eq!(roster.names, ["Alice", "Carol"]);
+        assert!(roster.apply(ControlMessage::RosterLeave { version: 6, name: "Alice".to_string() }));
+        assert_eq!(roster.names, ["Carol"]);
+        assert_eq!(roster.version, 6);
+    }
+
+    #[test]
+    fn roster_ignores_updates_covered_by_the_snapshot() {
+        let mut roster = Roster::default();
+        roster.apply(snapshot(5, &["Alice", "Bob"]));
+        assert!(!roster.apply(ControlMessage::RosterJoin { version: 5, name: "Bob".to_string() }));
+        assert!(!roster.apply(ControlMessage::RosterLeave { version: 4, name: "Alice".to_string() }));
+        assert!(!roster.apply(ControlMessage::RosterRename { version: 3, old: "Bob".to_string(), new: "Dave".to_string() }));
+        assert_eq!(roster.names, ["Alice", "Bob"]);
+        assert_eq!(roster.version, 5);
+    }
+
+    #[test]
+    fn roster_snapshot_replaces_the_view() {
+        let mut roster = Roster::default();
+        roster.apply(snapshot(2, &["Alice"]));
+        roster.apply(ControlMessage::RosterJoin { version: 3, name: "Bob".to_string() });
+        assert!(roster.apply(snapshot(9, &["Carol"])));
+        assert_eq!(roster.names, ["Carol"]);
+        assert_eq!(roster.version, 9);
+    }
+
+    #[test]
+    fn roster_passes_other_messages_through() {
+        let mut roster = Roster::default();
+        assert!(roster.apply(ControlMessage::System));
+        assert!(roster.names.is_empty());
+    }
+
+    #[test]
+    fn reconnects_back_off_and_stop_at_the_attempt_limit() {
+        let mut reconnects = Reconnects::default();
+        let quick = Some(Duration::from_secs(1));
+        let mut delays = Vec::new();
+        // Alternate quick disconnects with failed attempts; both use up the count
+        for i in 0..RECONNECT_ATTEMPTS {
+            let lasted = if i % 2 == 0 { quick } else { None };
+            delays.push(reconnects.next_delay(lasted).expect("attempt within the limit"));
+        }
+        assert_eq!(delays[0], Duration::from_secs(RECONNECT_DELAY_SECS));
+        assert!(delays.windows(2).all(|w| w[1] == w[0] * 2));
+        assert!(reconnects.next_delay(quick).is_none());
+        assert!(reconnects.next_delay(None).is_none());
+    }
+
+    #[test]
+    fn a_stable_session_starts_the_reconnect_count_over() {
+        let mut reconnects = Reconnects::default();
+        for _ in 0..RECONNECT_ATTEMPTS {
+            reconnects.next_delay(None);
+        }
+        assert!(reconnects.next_delay(None).is_none());
+        let stable = Some(Duration::from_secs(RECONNECT_STABLE_SECS));
+        assert_eq!(reconnects.next_delay(stable), Some(Duration::from_secs(RECONNECT_DELAY_SECS)));
+        assert_eq!(reconnects.attempts, 1);
+    }
+
+    // Plays a no-auth SOCKS5 proxy for one connection, answering CONNECT with
+    // `reply_code`. The task yields the CONNECT request it received
+    async fn mock_socks5_proxy(reply_code: u8) -> (String, tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let proxy = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [0x05, 0x01, 0x00]);
+            stream.write_all(&[0x05, 0x00]).await.unwrap();
+            let mut request = vec![0u8; 5];
+            stream.read_exact(&mut request).await.unwrap();
+            let rest = request[4] as usize + 2;
+            request.resize(5 + rest, 0);
+            stream.read_exact(&mut request[5..]).await.unwrap();
+            stream.write_all(&[0x05, reply_code, 0x00, 0x01, 10, 0, 0, 1, 0x1f, 0x90]).await.unwrap();
+            if reply_code == 0x00 {
+                stream.write_all(b"relayed").await.unwrap();
+            }
+            request
+        });
+        (addr, proxy)
+    }
+
+    #[tokio::test]
+    async fn socks5_connect_sends_a_domain_connect_without_auth() {
+        let (proxy_addr, proxy) = mock_socks5_proxy(0x00).await;
+        let mut stream = socks5_connect(&proxy_addr, "chat.example", 8080).await.unwrap();
+        let mut relayed = [0u8; 7];
+        stream.read_exact(&mut relayed).await.unwrap();
+        assert_eq!(&relayed, b"relayed");
+        let mut expected = vec![0x05, 0x01, 0x00, 0x03, 12];
+        expected.extend_from_slice(b"chat.example");
+        expected.extend_from_slice(&8080u16.to_be_bytes());
+        assert_eq!(proxy.await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn socks5_connect_reports_the_proxy_failure_code() {
+        let (proxy_addr, proxy) = mock_socks5_proxy(0x05).await;
+        let err = socks5_connect(&proxy_addr, "chat.example", 8080).await.unwrap_err();
+        assert_eq!(err.to_string(), "could not reach chat.example:8080: connection refused");
+        proxy.await.unwrap();
+    }
+
+    fn chat(sender: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            sender: sender.to_string(),
+            content: content.to_string(),
+            control: None,
+            rich: None,
+            meta: None,
+        }
+    }
+
+    fn read_log(path: &std::path::Path) -> Vec<serde_json::Value> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn message_log_appends_in_order_and_rotates() {
+        let dir = std::env::temp_dir().join(format!("message-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("messages.jsonl");
+        let path_str = path.to_str().unwrap();
+
+        let mut log = MessageLog::open(path_str).unwrap();
+        log.record_within("out", &chat("Alice", "hi"), 1024).unwrap();
+        log.record_within("in", &chat("Bob", "hello"), 1024).unwrap();
+        let records = read_log(&path);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["direction"], "out");
+        assert_eq!(records[0]["message"]["content"], "hi");
+        assert_eq!(records[1]["direction"], "in");
+        assert_eq!(records[1]["message"]["sender"], "Bob");
+        assert!(records[0]["timestamp_ms"].as_u64().unwrap() <= records[1]["timestamp_ms"].as_u64().unwrap());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        // Already past the limit, so the next record starts a fresh file
+        log.record_within("in", &chat("Bob", "bye"), 1).unwrap();
+        let rotated = dir.join("messages.jsonl.1");
+        assert_eq!(read_log(&rotated).len(), 2);
+        let records = read_log(&path);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["message"]["content"], "bye");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&rotated).unwrap().permissions().mode() & 0o777, 0o600);
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn echo(sender: &str, content: &str, id: u64) -> ChatMessage {
+        ChatMessage {
+            meta: Some(MessageMeta { id, timestamp_ms: 0 }),
+            ..chat(sender, content)
+        }
+    }
+
+    #[test]
+    fn echo_confirms_own_messages_by_server_id() {
+        let mut pending = PendingEchoes::default();
+        pending.sent("hi");
+        pending.sent("how are you");
+        assert_eq!(pending.reconcile(&echo("Alice", "hi", 7), Some("Alice")), Echo::Confirmed(7));
+        assert_eq!(pending.reconcile(&echo("Alice", "how are you", 9), Some("Alice")), Echo::Confirmed(9));
+        // Nothing left to match, so a repeat is shown like anyone else's message
+        assert_eq!(pending.reconcile(&echo("Alice", "hi", 10), Some("Alice")), Echo::NotOwn);
+    }
+
+    #[test]
+    fn echo_ignores_other_senders_and_messages_without_meta() {
+        let mut pending = PendingEchoes::default();
+        pending.sent("hi");
+        assert_eq!(pending.reconcile(&echo("Bob", "hi", 1), Some("Alice")), Echo::NotOwn);
+        assert_eq!(pending.reconcile(&chat("Alice", "hi"), Some("Alice")), Echo::NotOwn);
+        assert_eq!(pending.reconcile(&echo("Alice", "hi", 1), None), Echo::NotOwn);
+        assert_eq!(pending.reconcile(&echo("Alice", "hi", 2), Some("Alice")), Echo::Confirmed(2));
+    }
+
+    #[test]
+    fn echo_reports_altered_and_skips_dropped_messages() {
+        let mut pending = PendingEchoes::default();
+        pending.sent("buy spam");
+        pending.sent("dropped");
+        pending.sent("fine");
+        assert_eq!(pending.reconcile(&echo("Alice", "buy ****", 3), Some("Alice")), Echo::Altered(3));
+        // "dropped" never came back; the next echo matches past it
+        assert_eq!(pending.reconcile(&echo("Alice", "fine", 4), Some("Alice")), Echo::Confirmed(4));
+        assert!(pending.sent.is_empty());
+    }
+
+    #[test]
+    fn echo_forgets_the_oldest_sends_past_the_limit() {
+        let mut pending = PendingEchoes::default();
+        for i in 0..MAX_PENDING_ECHOES + 1 {
+            pending.sent(&i.to_string());
+        }
+        assert_eq!(pending.sent.len(), MAX_PENDING_ECHOES);
+        assert_eq!(pending.sent.front().map(String::as_str), Some("1"));
+    }
+
+    #[test]
+    fn meta_is_read_from_relayed_messages() {
+        let json = r#"{"sender":"Alice","content":"hi","meta":{"id":42,"timestamp_ms":1700000000000}}"#;
+        let msg: ChatMessage = serde_json::from_str(json).unwrap();
+        let meta = msg.meta.expect("meta");
+        assert_eq!((meta.id, meta.timestamp_ms), (42, 1_700_000_000_000));
+        // Clients never send it themselves
+        assert!(!serde_json::to_string(&chat("Alice", "hi")).unwrap().contains("meta"));
+    }
+
+    #[tokio::test]
+    async fn read_name_takes_the_first_non_empty_line() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let rx = Mutex::new(rx);
+        for line in ["", "   ", "  Alice  ", "hello"] {
+            tx.send(line.to_string()).unwrap();
+        }
+        assert_eq!(read_name(&rx).await.as_deref(), Some("Alice"));
+        // Later lines are left for the chat loop
+        assert_eq!(rx.lock().await.recv().await.as_deref(), Some("hello"));
+    }
+
+    #[tokio::test]
+    async fn read_name_gives_up_on_quit_or_end_of_input() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let rx = Mutex::new(rx);
+        tx.send("/quit".to_string()).unwrap();
+        assert_eq!(read_name(&rx).await, None);
+        drop(tx);
+        assert_eq!(read_name(&rx).await, None);
+    }
+
+    const NEWER_CONTROL: &str = r#"{"sender":"Server","content":"Vote: lunch?","control":{"type":"poll","options":["yes","no"]}}"#;
+
+    #[test]
+    fn unknown_control_shows_its_fallback_when_lenient() {
+        let msg: ChatMessage = serde_json::from_str(NEWER_CONTROL).unwrap();
+        assert!(matches!(msg.control, Some(ControlMessage::Unknown)));
+        assert_eq!(unknown_control_line(&msg, false).as_deref(), Some("Server: Vote: lunch?"));
+    }
+
+    #[test]
+    fn unknown_control_is_dropped_when_strict() {
+        let msg: ChatMessage = serde_json::from_str(NEWER_CONTROL).unwrap();
+        assert_eq!(unknown_control_line(&msg, true), None);
+    }
+
+    #[test]
+    fn rich_payload_round_trips_and_renders() {
+        let InputCommand::Rich { content_type, data } = parse_input(r#"/rich application/vnd.poll+json {"question":"Lunch?","options":["yes","no"]}"#) else {
+            panic!("expected a rich payload");
+        };
+        let sent = ChatMessage {
+            sender: String::new(),
+            content: format!("[{} payload]", content_type),
+            control: None,
+            rich: Some(RichPayload { content_type, data }),
+            meta: None,
+        };
+        let wire = serde_json::to_string(&sent).unwrap();
+        let received: ChatMessage = serde_json::from_str(&wire).unwrap();
+        assert_eq!(received.content, "[application/vnd.poll+json payload]");
+        let rich = received.rich.expect("rich payload");
+        assert_eq!(rich.data["options"][1], "no");
+        assert_eq!(
+            rich.render().as_deref(),
+            Some(r#"[application/vnd.poll+json] {"options":["yes","no"],"question":"Lunch?"}"#)
+        );
+    }
+
+    #[test]
+    fn rich_payload_of_another_type_falls_back_to_the_summary() {
+        let rich = RichPayload {
+            content_type: "image/png".to_string(),
+            data: serde_json::json!("iVBORw0KGgo="),
+        };
+        assert_eq!(rich.render(), None);
+    }
+
+    #[test]
+    fn incoming_lines_go_above_the_partly_typed_prompt() {
+        assert_eq!(render_above_prompt("Bob: hi", Some("hel")), "\r\x1b[2KBob: hi\n> hel");
+        assert_eq!(render_above_prompt("Bob: hi", Some("")), "\r\x1b[2KBob: hi\n> ");
+    }
+
+    #[test]
+    fn a_final_line_leaves_no_prompt_behind() {
+        assert_eq!(render_above_prompt("Server disconnected", None), "\r\x1b[2KServer disconnected\n");
+    }
+
+    #[test]
+    fn the_prompt_shows_what_has_been_typed() {
+        assert_eq!(render_prompt(""), "> ");
+        assert_eq!(render_prompt("héllo"), "> héllo");
+    }
+
+    #[tokio::test]
+    async fn forwarded_input_ends_the_session_at_end_of_file() {
+        let (input_tx, mut input_rx) = mpsc::unbounded_channel();
+        assert_eq!(forward_lines(&b"hello\n\nlast line"[..], input_tx).await, InputEnd::Eof);
+        assert_eq!(input_rx.recv().await.as_deref(), Some("hello"));
+        assert_eq!(input_rx.recv().await.as_deref(), Some(""));
+        assert_eq!(input_rx.recv().await.as_deref(), Some("last line"));
+        // The sender is gone, which the session takes as a disconnect
+        assert_eq!(input_rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn forwarded_input_tells_a_read_failure_from_end_of_file() {
+        let (input_tx, mut input_rx) = mpsc::unbounded_channel();
+        assert_eq!(forward_lines(&b"hello\n\xff\xfe\nafter\n"[..], input_tx).await, InputEnd::Failed);
+        assert_eq!(input_rx.recv().await.as_deref(), Some("hello"));
+        assert_eq!(input_rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn forwarding_stops_once_the_session_stops_reading() {
+        let (input_tx, input_rx) = mpsc::unbounded_channel();
+        drop(input_rx);
+        assert_eq!(forward_lines(&b"hello\n"[..], input_tx).await, InputEnd::Closed);
+    }
+
+    #[tokio::test]
+    async fn end_of_input_at_the_name_prompt_gives_no_name() {
+        let (input_tx, input_rx) = mpsc::unbounded_channel();
+        forward_lines(&b"\n  \n"[..], input_tx).await;
+        assert_eq!(read_name(&Mutex::new(input_rx)).await, None);
+    }
+
+    #[test]
+    fn a_system_notice_is_shown_apart_from_chat_and_optionally_logged() {
+        // As the server sends it for "/sys Restarting at noon"
+        let json = r#"{"sender":"","content":"Restarting at noon","control":{"type":"system"}}"#;
+        let notice: ChatMessage = serde_json::from_str(json).unwrap();
+        assert!(matches!(notice.control, Some(ControlMessage::System)));
+        assert_eq!(system_notice_line(&notice), "*** Restarting at noon ***");
+        assert!(logs_incoming(&notice, true));
+        assert!(!logs_incoming(&notice, false));
+
+        // Ordinary chat is logged either way
+        let message = chat("Server", "Restarting at noon");
+        assert!(logs_incoming(&message, false));
+        assert!(logs_incoming(&message, true));
+    }
+
+    // Sessions for both ends of an NN handshake, enough to exercise transport messages
+    fn session_pair() -> (NoiseSession, NoiseSession) {
+        let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+        let mut initiator = Builder::new(params.clone()).build_initiator().unwrap();
+        let mut responder = Builder::new(params).build_responder().unwrap();
+        let (mut message, mut payload) = ([0u8; 128], [0u8; 128]);
+        let len = initiator.write_message(&[], &mut message).unwrap();
+        responder.read_message(&message[..len], &mut payload).unwrap();
+        let len = responder.write_message(&[], &mut message).unwrap();
+        initiator.read_message(&message[..len], &mut payload).unwrap();
+        let session = |state: HandshakeState| {
+            let hash = state.get_handshake_hash().to_vec();
+            NoiseSession::new(state.into_transport_mode().unwrap(), hash)
+        };
+        (session(initiator), session(responder))
+    }
+
+    // Stands in for a payload serde_json can't represent
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("payload has no JSON form"))
+        }
+    }
+
+    #[test]
+    fn a_message_that_fails_to_serialize_is_reported_and_uses_no_nonce() {
+        let (mut client, mut server) = session_pair();
+        let err = client.encrypt_message(&Unserializable).unwrap_err();
+        assert!(matches!(err, NoiseError::SerializationError(_)));
+        // What the user is shown as "Message could not be sent: ..."
+        assert_eq!(err.to_string(), "Serialization error: payload has no JSON form");
+        assert_eq!(client.sending_nonce(), 0);
+
+        let encrypted = client.encrypt_message(&chat("Alice", "after")).unwrap();
+        let decrypted: ChatMessage = serde_json::from_slice(&server.decrypt(&encrypted).unwrap()).unwrap();
+        assert_eq!(decrypted.content, "after");
+    }
+}
@@ -1,404 +1,4036 @@
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::{HashMap, HashSet};
 use std::io::{self, Write};
-use tokio::sync::{Mutex, broadcast};
-use futures_util::{SinkExt, StreamExt};
+use tokio::sync::{Mutex, Notify, Semaphore, broadcast, broadcast::error::{RecvError, TryRecvError}};
+use futures_util::{Sink, SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, timeout_at, Duration, Instant};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{header::{ORIGIN, SEC_WEBSOCKET_PROTOCOL}, HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::{accept_hdr_async_with_config, tungstenite::Message};
+use sha2::{Digest, Sha256};
+use socket2::{SockRef, TcpKeepalive};
+use snow::params::NoiseParams;
 use snow::{Builder, HandshakeState, TransportState};
 use std::error::Error;
 
-const NOISE_PATTERN: &str = "Noise_XXpsk2_25519_AESGCM_SHA256";
+// Noise protocol components, assembled into Noise_<pattern>_<dh>_<cipher>_<hash>.
+// Must match the other side
+const HANDSHAKE_PATTERN: &str = "XXpsk2";
+const DH_FUNCTION: &str = "25519";
+const CIPHER: &str = "AESGCM";  // or "ChaChaPoly"
+const HASH: &str = "SHA256";  // or "SHA512", "BLAKE2s", "BLAKE2b"
+// Full protocol name, used instead of the components above when set
+const NOISE_PATTERN_OVERRIDE: Option<&str> = None;  // e.g. Some("Noise_XXpsk2_25519_ChaChaPoly_BLAKE2s")
 const PSK: &[u8; 32] = b"my_super_secret_pre_shared_key!!";
+// Addresses to accept connections on, e.g. both IPv4 and IPv6 loopback
+const SERVER_ADDRESSES: &[&str] = &["127.0.0.1:8080"];
+// true: exit if any address fails to bind; false: serve on the ones that did
+const BIND_FAIL_FAST: bool = true;
+// Time a new connection gets to finish the handshake and send its name
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+// Handshakes in progress at once; further connections wait up to
+// HANDSHAKE_QUEUE_SECS for a slot and are then refused with a 503
+const MAX_CONCURRENT_HANDSHAKES: usize = 64;
+const HANDSHAKE_QUEUE_SECS: u64 = 5;
+// How long a disconnected client stays in the roster; rejoining under the same
+// name within it hides the leave/join pair. 0 removes clients immediately
+const RECONNECT_GRACE_SECS: u64 = 0;
+// After /drain, how long existing clients may stay before the server exits
+const DRAIN_TIMEOUT_SECS: u64 = 300;
+// After sending the clients left at the drain timeout a shutdown notice, how
+// long to wait for their connections to close before exiting anyway
+const SHUTDOWN_NOTICE_SECS: u64 = 5;
+// Cap on simultaneous connections from one source IP, so a single host can't
+// take every slot. Clients behind a shared NAT count together; raise it if needed
+const MAX_CONNECTIONS_PER_IP: Option<usize> = None;  // e.g. Some(8)
+// This many failed or timed-out handshakes from one IP within the window bans
+// it for HANDSHAKE_BAN_SECS, to slow down PSK guessing
+const HANDSHAKE_FAILURE_LIMIT: Option<usize> = None;  // e.g. Some(5)
+const HANDSHAKE_FAILURE_WINDOW_SECS: u64 = 60;
+const HANDSHAKE_BAN_SECS: u64 = 300;
+// Send small messages immediately instead of letting Nagle's algorithm batch
+// them, trading a little bandwidth for latency
+const TCP_NODELAY: bool = true;
+// Probe idle connections so dead peers are noticed; None keeps the OS default
+const TCP_KEEPALIVE_SECS: Option<u64> = None;  // e.g. Some(60)
+// Kernel send and receive buffer size per connection; None keeps the OS default
+const SOCKET_BUFFER_SIZE: Option<usize> = None;
+// Longest name a client may choose, in characters. Names may contain letters,
+// digits and NAME_EXTRA_CHARS; anything else (spaces, control characters) is refused
+const MAX_NAME_LEN: usize = 32;
+const NAME_EXTRA_CHARS: &str = "_-.";
+// Control types this version doesn't know (from a newer client) are relayed as
+// plain chat; set to refuse such messages instead
+const REJECT_UNKNOWN_CONTROL: bool = false;
+// Print extra diagnostics (never secrets) when something goes wrong
+const DEBUG_LOGGING: bool = false;
+// WebSocket subprotocol naming this wire protocol; bump on incompatible changes
+const SUBPROTOCOL: &str = "noise-chat-v2";
+// Upgrade request header carrying the client's full Noise protocol name. It is
+// sent outside Noise because with a PSK pattern even the first handshake
+// payload is encrypted, so a mismatch could otherwise only show up as a failed decrypt
+const NOISE_PROTOCOL_HEADER: &str = "x-noise-protocol";
+// Browser origins allowed to connect; requests without an Origin header (native
+// clients) are unaffected. None accepts every origin
+const ALLOWED_ORIGINS: Option<&[&str]> = None;  // e.g. Some(&["https://chat.example.com"])
+// Broadcasts arriving within this window share one encrypted frame; 0 disables
+const COALESCE_WINDOW_MS: u64 = 0;
+// true flushes every broadcast frame as it is written (lowest latency); false
+// writes everything already queued for a client and flushes once (fewer writes)
+const FLUSH_EACH_FRAME: bool = false;
+// Messages buffered per receiver before a slow client starts missing broadcasts
+const BROADCAST_CAPACITY: usize = 100;
+// A broadcast delivery is slow if sending takes this long or finds or leaves
+// this many messages queued; this many slow deliveries in a row flags the client
+const SLOW_SEND_MS: u64 = 200;
+const SLOW_QUEUE_DEPTH: usize = BROADCAST_CAPACITY / 2;
+const SLOW_CONSUMER_STREAK: u32 = 5;
+// Interval between WebSocket pings used to measure each client's round-trip time
+const PING_INTERVAL_SECS: Option<u64> = Some(15);  // None sends no pings
+// Noise caps every message at 65535 bytes; each WebSocket message carries exactly one
+const NOISE_MAX_MESSAGE_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+// Largest payload either side puts in a handshake message: SUBPROTOCOL in the
+// second and third. The first is empty, as the initiator's Noise protocol name
+// travels in the NOISE_PROTOCOL_HEADER upgrade header instead
+const MAX_HANDSHAKE_PAYLOAD_LEN: usize = 64;
+// Largest plaintext a single Noise transport message can carry (minus the AEAD tag)
+const MAX_PLAINTEXT_LEN: usize = NOISE_MAX_MESSAGE_LEN - TAG_LEN;
+// First plaintext byte of a coalesced frame; plain JSON messages start with '{'
+const BATCH_MARKER: u8 = 0;
+// Persistent static keypair; None generates a fresh one for every handshake
+const STATIC_KEY_FILE: Option<&str> = None;  // e.g. Some("server_static.key")
+// Names refused at join, one per line; /ban and /unban keep it up to date
+const BANNED_NAMES_FILE: Option<&str> = None;  // e.g. Some("banned_names.txt")
+// Words masked (or, with DROP_BLOCKED_MESSAGES, rejected) in client messages
+const BLOCKED_WORDS: Option<&[&str]> = None;  // e.g. Some(&["spam", "scam"])
+const DROP_BLOCKED_MESSAGES: bool = false;
+// Max ciphertext bytes a client may send per QUOTA_WINDOW_SECS; None disables
+const CLIENT_BYTE_QUOTA: Option<u64> = None;  // e.g. Some(1_000_000)
+const QUOTA_WINDOW_SECS: u64 = 60;
+// Over quota: false stops reading from the client until the window frees up,
+// true disconnects it with a "rate limited" notice
+const DISCONNECT_OVER_QUOTA: bool = false;
+// Largest serialized rich payload relayed between clients
+const MAX_RICH_PAYLOAD_LEN: usize = 16 * 1024;
+// Send clients their own messages back, with the server-assigned id and
+// timestamp, so UIs can confirm what was actually broadcast
+const ECHO_OWN_MESSAGES: bool = false;
+// Lets clients run console commands with `/admin <token> <command>`; None keeps
+// them on the local console only
+const ADMIN_TOKEN: Option<&str> = None;
+// Wrong admin tokens a connection may send before it is disconnected, and the
+// pause after each one, so a chat user can't guess the token at message rate
+const ADMIN_ATTEMPT_LIMIT: u32 = 3;
+const ADMIN_RETRY_DELAY_SECS: u64 = 2;
+// Lets clients join as read-only observers with this token (the client's
+// OBSERVER_TOKEN); None refuses observers. Keep it different from ADMIN_TOKEN
+const OBSERVER_TOKEN: Option<&str> = None;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct ChatMessage {
     sender: String,
     content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    control: Option<ControlMessage>,
+    // Structured payload; `content` then holds a plain-text summary
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rich: Option<RichPayload>,
+    // Assigned by the server to every relayed client message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    meta: Option<MessageMeta>,
 }
 
-#[derive(Debug, Clone)]
-struct ServerCommand {
-    target: Option<String>,  // None = broadcast, Some(name) = send to specific client
-    message: ChatMessage,
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct MessageMeta {
+    id: u64,
+    timestamp_ms: u64,
 }
 
-#[derive(Debug)]
-enum NoiseError {
-    HandshakeError(String),
-    EncryptionError(String),
-    DecryptionError(String),
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RichPayload {
+    content_type: String,
+    data: serde_json::Value,
 }
 
-impl std::fmt::Display for NoiseError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            NoiseError::HandshakeError(msg) => write!(f, "Handshake error: {}", msg),
-            NoiseError::EncryptionError(msg) => write!(f, "Encryption error: {}", msg),
-            NoiseError::DecryptionError(msg) => write!(f, "Decryption error: {}", msg),
+impl RichPayload {
+    /// Why the payload can't be relayed, if it can't: over `limit` bytes
+    /// serialized (MAX_RICH_PAYLOAD_LEN), or not encodable at all.
+    fn problem(&self, limit: usize) -> Option<String> {
+        match serde_json::to_vec(&self.data) {
+            Ok(data) if data.len() > limit => Some(format!("payload is {} bytes, the limit is {}", data.len(), limit)),
+            Ok(_) => None,
+            Err(e) => Some(format!("payload could not be encoded: {}", e)),
         }
     }
 }
 
-impl Error for NoiseError {}
+// Control messages carry a human-readable fallback in `content`, so clients
+// that don't understand them still show something sensible.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ControlMessage {
+    RosterSnapshot { version: u64, names: Vec<String> },
+    RosterJoin { version: u64, name: String },
+    RosterLeave { version: u64, name: String },
+    RosterRename { version: u64, old: String, new: String },
+    // Sent by clients: change name, or deliver `content` to a single client
+    Rename { name: String },
+    Direct { to: String },
+    // Sent by clients: run a console command, see ADMIN_TOKEN
+    Admin { token: String, command: String },
+    // Sent by clients instead of a name: watch all traffic without joining
+    Observe { token: String },
+    // Last message before the server closes the connection
+    Disconnect { reason: DisconnectReason, reconnect: bool },
+    // Asks clients to reconnect to another server, e.g. before this one stops
+    Redirect { url: String },
+    // Operator announcement, shown apart from the conversation
+    System,
+    // Any type added by a newer peer; its `content` fallback still applies
+    #[serde(other)]
+    Unknown,
+}
 
-struct NoiseSession {
-    transport: TransportState,
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum DisconnectReason {
+    // Over CLIENT_BYTE_QUOTA with DISCONNECT_OVER_QUOTA
+    RateLimited,
+    // Fell more than BROADCAST_CAPACITY messages behind
+    TooSlow,
+    // Chose a name that fails validate_name
+    InvalidName,
+    // Name is on the ban list
+    Banned,
+    // Asked for observer access with a wrong token
+    Unauthorized,
+    // The server is draining or stopping
+    Shutdown,
 }
 
-impl NoiseSession {
-    fn new(transport: TransportState) -> Self {
-        Self { transport }
+impl DisconnectReason {
+    /// Whether reconnecting right away is worthwhile; a rate-limited client
+    /// would only be dropped again.
+    fn reconnectable(self) -> bool {
+        match self {
+            DisconnectReason::RateLimited | DisconnectReason::Banned | DisconnectReason::Unauthorized => false,
+            DisconnectReason::TooSlow | DisconnectReason::InvalidName | DisconnectReason::Shutdown => true,
+        }
     }
+}
 
-    fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, NoiseError> {
-        let mut ciphertext = vec![0u8; plaintext.len() + 16];
-        let len = self
-            .transport
-            .write_message(plaintext, &mut ciphertext)
-            .map_err(|e| NoiseError::EncryptionError(e.to_string()))?;
-        ciphertext.truncate(len);
-        Ok(ciphertext)
+// Traffic counters for one connection, in ciphertext bytes
+#[derive(Default)]
+struct ConnectionStats {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    slow_sends: AtomicU64,
+    // Smoothed round-trip time in microseconds, 0 until the first pong
+    rtt_us: AtomicU64,
+}
+
+impl ConnectionStats {
+    /// Folds a new sample into the smoothed RTT, weighting it 1/8 as TCP does.
+    fn record_rtt(&self, sample_us: u64) {
+        let srtt = self.rtt_us.load(Ordering::Relaxed);
+        let updated = if srtt == 0 {
+            sample_us.max(1)
+        } else {
+            (srtt * 7 + sample_us) / 8
+        };
+        self.rtt_us.store(updated.max(1), Ordering::Relaxed);
     }
 
-    fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, NoiseError> {
-        let mut plaintext = vec![0u8; ciphertext.len()];
-        let len = self
-            .transport
-            .read_message(ciphertext, &mut plaintext)
-            .map_err(|e| NoiseError::DecryptionError(e.to_string()))?;
-        plaintext.truncate(len);
-        Ok(plaintext)
+    fn rtt_ms(&self) -> Option<f64> {
+        match self.rtt_us.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(us as f64 / 1000.0),
+        }
     }
 }
 
-fn create_responder() -> Result<HandshakeState, NoiseError> {
-    let builder = Builder::new(NOISE_PATTERN.parse().unwrap());
-    let keypair = builder.generate_keypair().map_err(|e| NoiseError::HandshakeError(e.to_string()))?;
-    
-    builder
-        .local_private_key(&keypair.private)
-        .psk(2, PSK)
-        .build_responder()
-        .map_err(|e| NoiseError::HandshakeError(e.to_string()))
+struct ClientInfo {
+    name: String,
+    addr: SocketAddr,
+    stats: Arc<ConnectionStats>,
+    // Short authentication string for this client's session, see /verify
+    sas: String,
+    // false while waiting out RECONNECT_GRACE_SECS after a disconnect
+    connected: bool,
 }
 
-#[tokio::main(flavor = "multi_thread")]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "127.0.0.1:8080";
-    let listener = TcpListener::bind(&addr).await?;
-    println!("Server listening on: {}", addr);
-    println!("Using Noise protocol: {}", NOISE_PATTERN);
-    println!("Commands: '@ClientName message' to send to specific client, or 'message' to broadcast");
-
-    let (broadcast_tx, _) = broadcast::channel::<ChatMessage>(100);
-    let (server_cmd_tx, _) = broadcast::channel::<ServerCommand>(100);
-    let clients = Arc::new(Mutex::new(HashMap::new()));
-    let client_counter = Arc::new(Mutex::new(0u32));
-
-    // Server input task
-    let server_cmd_tx_clone = server_cmd_tx.clone();
-    let clients_clone = clients.clone();
-    tokio::spawn(async move {
-        let stdin = tokio::io::stdin();
-        let reader = BufReader::new(stdin);
-        let mut lines = reader.lines();
+// Server-wide counters for the /stats console command
+struct ServerStats {
+    started: Instant,
+    joined: AtomicU64,
+    failed_handshakes: AtomicU64,
+    handshake_timeouts: AtomicU64,
+    messages_relayed: AtomicU64,
+    bans: AtomicU64,
+}
 
-        print!("> ");
-        io::stdout().flush().unwrap();
+impl ServerStats {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            joined: AtomicU64::new(0),
+            failed_handshakes: AtomicU64::new(0),
+            handshake_timeouts: AtomicU64::new(0),
+            messages_relayed: AtomicU64::new(0),
+            bans: AtomicU64::new(0),
+        }
+    }
+}
 
-        while let Ok(Some(line)) = lines.next_line().await {
-            let line = line.trim();
+// Every change to `clients` bumps `version` while the lock is held, so a
+// client can drop deltas already reflected in the snapshot it was sent.
+#[derive(Default)]
+struct Roster {
+    version: u64,
+    clients: HashMap<u32, ClientInfo>,
+}
 
-            if line.is_empty() {
-                print!("> ");
-                io::stdout().flush().unwrap();
-                continue;
-            }
+impl Roster {
+    fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.clients.values().map(|c| c.name.clone()).collect();
+        names.sort();
+        names
+    }
 
-            let (target, content) = if line.starts_with('@') {
-                // Targeted message: @ClientName message
-                if let Some(space_pos) = line.find(' ') {
-                    let name = &line[1..space_pos];
-                    let msg = &line[space_pos + 1..];
-                    (Some(name.to_string()), msg.to_string())
-                } else {
-                    println!("Invalid format. Use: @ClientName message");
-                    print!("> ");
-                    io::stdout().flush().unwrap();
-                    continue;
+    /// Who holds `name`. The one rule for joining and renaming: a connected
+    /// client's name is taken, while a name left by a client in its grace
+    /// period goes to whoever claims it next.
+    fn holder(&self, name: &str) -> NameHolder {
+        let mut holder = NameHolder::Free;
+        for (&id, client) in &self.clients {
+            if client.name == name {
+                if client.connected {
+                    return NameHolder::Connected;
                 }
-            } else {
-                // Broadcast message
-                (None, line.to_string())
-            };
-
-            let cmd = ServerCommand {
-                target: target.clone(),
-                message: ChatMessage {
-                    sender: "Server".to_string(),
-                    content: content.clone(),
-                },
-            };
+                holder = NameHolder::Away(id);
+            }
+        }
+        holder
+    }
 
-            if let Some(name) = &target {
-                let clients_map = clients_clone.lock().await;
-                if clients_map.values().any(|n| n == name) {
-                    println!("To {}: {}", name, content);
-                } else {
-                    println!("Client '{}' not found", name);
-                    print!("> ");
-                    io::stdout().flush().unwrap();
-                    continue;
-                }
-            } else {
-                println!("Broadcast: {}", content);
+    /// Adds a client, returning the join to announce. Taking over an away
+    /// entry leaves the names unchanged, so there is nothing to announce.
+    fn join(&mut self, client_id: u32, client: ClientInfo) -> Result<Option<ControlMessage>, String> {
+        let announce = match self.holder(&client.name) {
+            NameHolder::Connected => return Err(format!("Name '{}' is already taken", client.name)),
+            NameHolder::Away(old_id) => {
+                self.clients.remove(&old_id);
+                None
+            }
+            NameHolder::Free => {
+                self.version += 1;
+                Some(ControlMessage::RosterJoin {
+                    version: self.version,
+                    name: client.name.clone(),
+                })
             }
+        };
+        self.clients.insert(client_id, client);
+        Ok(announce)
+    }
 
-            let _ = server_cmd_tx_clone.send(cmd);
-            print!("> ");
-            io::stdout().flush().unwrap();
+    /// Renames a client, returning the updates to announce in order. An away
+    /// entry holding the new name is dropped first and announced as leaving.
+    fn rename(&mut self, client_id: u32, new_name: &str) -> Result<Vec<ControlMessage>, String> {
+        let mut updates = Vec::new();
+        match self.holder(new_name) {
+            NameHolder::Connected => return Err(format!("Name '{}' is already taken", new_name)),
+            NameHolder::Away(old_id) => updates.extend(self.remove(old_id)),
+            NameHolder::Free => {}
         }
-    });
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return Ok(updates);
+        };
+        let old = std::mem::replace(&mut client.name, new_name.to_string());
+        self.version += 1;
+        updates.push(ControlMessage::RosterRename {
+            version: self.version,
+            old,
+            new: new_name.to_string(),
+        });
+        Ok(updates)
+    }
 
-    loop {
-        if let Ok((stream, addr)) = listener.accept().await {
-            println!("New connection from: {}", addr);
-            let broadcast_tx = broadcast_tx.clone();
-            let server_cmd_tx = server_cmd_tx.clone();
-            let clients = clients.clone();
-            let client_counter = client_counter.clone();
-            
-            tokio::spawn(async move {
-                handle_connection(stream, broadcast_tx, server_cmd_tx, clients, client_counter).await;
-            });
+    /// Drops a client, returning the leave to announce, or None if it was
+    /// already gone (e.g. its entry was taken over).
+    fn remove(&mut self, client_id: u32) -> Option<ControlMessage> {
+        let client = self.clients.remove(&client_id)?;
+        self.version += 1;
+        Some(ControlMessage::RosterLeave {
+            version: self.version,
+            name: client.name,
+        })
+    }
+
+    fn snapshot(&self) -> ControlMessage {
+        ControlMessage::RosterSnapshot {
+            version: self.version,
+            names: self.names(),
         }
     }
 }
 
-async fn handle_connection(
-    stream: TcpStream,
-    broadcast_tx: broadcast::Sender<ChatMessage>,
-    server_cmd_tx: broadcast::Sender<ServerCommand>,
-    clients: Arc<Mutex<HashMap<u32, String>>>,
-    client_counter: Arc<Mutex<u32>>,
-) {
-    let ws_stream = match accept_async(stream).await {
-        Ok(ws) => ws,
-        Err(err) => {
-            eprintln!("Failed to accept WebSocket: {}", err);
-            return;
-        }
-    };
+enum NameHolder {
+    Free,
+    // A disconnected entry waiting out RECONNECT_GRACE_SECS
+    Away(u32),
+    Connected,
+}
 
-    println!("WebSocket connection established");
-    println!("Starting Noise handshake...");
+impl NameHolder {
+    /// Whether a direct message to `name` can be delivered. An away client
+    /// has no connection to queue it on, so the sender is told instead.
+    fn direct_target(&self, name: &str) -> Result<(), String> {
+        match self {
+            NameHolder::Connected => Ok(()),
+            NameHolder::Away(_) => Err(format!("Client '{}' is away; the message was not delivered", name)),
+            NameHolder::Free => Err(format!("Client '{}' not found", name)),
+        }
+    }
+}
 
-    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+/// Drops a `type` this server doesn't know so the message relays as plain
+/// chat, or with `reject` (REJECT_UNKNOWN_CONTROL) returns the sender's refusal.
+fn strip_unknown_control(msg: &mut ChatMessage, reject: bool) -> Result<(), String> {
+    if !matches!(msg.control, Some(ControlMessage::Unknown)) {
+        return Ok(());
+    }
+    if reject {
+        return Err("Your message was not delivered: this server does not support its type".to_string());
+    }
+    msg.control = None;
+    Ok(())
+}
 
-    let noise_session = match perform_noise_handshake_responder(&mut ws_sender, &mut ws_receiver).await {
-        Ok(session) => session,
-        Err(e) => {
-            eprintln!("Noise handshake failed: {}", e);
-            return;
-        }
+/// Wraps a roster update in the message everyone is sent, with a readable line
+/// for clients that show `content`.
+fn roster_notice(update: ControlMessage) -> ChatMessage {
+    let content = match &update {
+        ControlMessage::RosterJoin { name, .. } => format!("{} joined the chat", name),
+        ControlMessage::RosterLeave { name, .. } => format!("{} left the chat", name),
+        ControlMessage::RosterRename { old, new, .. } => format!("{} is now known as {}", old, new),
+        _ => String::new(),
     };
+    ChatMessage {
+        sender: "Server".to_string(),
+        content,
+        control: Some(update),
+        rich: None,
+        meta: None,
+    }
+}
 
-    println!("Secure channel established");
+// Open connections per source IP, for MAX_CONNECTIONS_PER_IP
+type IpConnections = Arc<std::sync::Mutex<HashMap<IpAddr, usize>>>;
 
-    let noise_session = Arc::new(Mutex::new(noise_session));
-    
-    // Request client name
-    let name_request = ChatMessage {
-        sender: "Server".to_string(),
-        content: "Please enter your name:".to_string(),
-    };
-    
-    match serde_json::to_string(&name_request) {
-        Ok(json) => {
-            let mut session = noise_session.lock().await;
-            if let Ok(encrypted) = session.encrypt(json.as_bytes()) {
-                if let Err(err) = ws_sender.send(Message::Binary(encrypted)).await {
-                    eprintln!("Failed to request name: {}", err);
-                    return;
-                }
-            }
+// One of an IP's connection slots; dropping it returns the slot, whichever
+// way the connection ends
+struct IpSlot {
+    connections: IpConnections,
+    ip: IpAddr,
+}
+
+impl IpSlot {
+    fn acquire(connections: &IpConnections, ip: IpAddr) -> Option<Self> {
+        Self::acquire_within(connections, ip, MAX_CONNECTIONS_PER_IP)
+    }
+
+    fn acquire_within(connections: &IpConnections, ip: IpAddr, limit: Option<usize>) -> Option<Self> {
+        let mut counts = connections.lock().unwrap();
+        let count = counts.entry(ip).or_insert(0);
+        if limit.is_some_and(|max| *count >= max) {
+            return None;
         }
-        Err(_) => return,
+        *count += 1;
+        Some(Self {
+            connections: Arc::clone(connections),
+            ip,
+        })
     }
+}
 
-    // Wait for client name
-    let client_name = if let Some(msg) = ws_receiver.next().await {
-        match msg {
-            Ok(Message::Binary(encrypted_data)) => {
-                let mut session = noise_session.lock().await;
-                match session.decrypt(&encrypted_data) {
-                    Ok(decrypted) => {
-                        match String::from_utf8(decrypted) {
-                            Ok(json_str) => {
-                                if let Ok(chat_msg) = serde_json::from_str::<ChatMessage>(&json_str) {
-                                    chat_msg.content
-                                } else {
-                                    return;
-                                }
-                            }
-                            Err(_) => return,
-                        }
-                    }
-                    Err(_) => return,
-                }
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut counts = self.connections.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
             }
-            _ => return,
         }
-    } else {
-        return;
-    };
+    }
+}
 
-    let client_id = {
-        let mut counter = client_counter.lock().await;
-        *counter += 1;
-        *counter
-    };
+// Names refused at join and rename, compared case-insensitively
+#[derive(Default)]
+struct NameBans {
+    names: HashSet<String>,
+}
 
-    clients.lock().await.insert(client_id, client_name.clone());
-    println!("{} joined the chat", client_name);
+impl NameBans {
+    fn load(path: &str) -> io::Result<Self> {
+        let names = match std::fs::read_to_string(path) {
+            Ok(contents) => contents
+                .lines()
+                .map(|line| Self::normalize(line.trim()))
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self { names })
+    }
 
-    // let join_msg = ChatMessage {
-    //     sender: "Server".to_string(),
-    //     content: format!("{} joined the chat", client_name),
-    // };
-    // let _ = broadcast_tx.send(join_msg);
+    /// The form names are stored and compared in.
+    fn normalize(name: &str) -> String {
+        name.to_lowercase()
+    }
 
-    let mut broadcast_rx = broadcast_tx.subscribe();
-    let mut server_cmd_rx = server_cmd_tx.subscribe();
-    let noise_session_recv = Arc::clone(&noise_session);
-    let ws_sender = Arc::new(Mutex::new(ws_sender));
-    let ws_sender_broadcast = Arc::clone(&ws_sender);
-    let ws_sender_server = Arc::clone(&ws_sender);
-    let noise_session_server = Arc::clone(&noise_session);
-    let client_name_clone = client_name.clone();
-    let client_name_server = client_name.clone();
+    fn is_banned(&self, name: &str) -> bool {
+        self.names.contains(&Self::normalize(name))
+    }
 
-    // Broadcast messages to this client
-    let broadcast_task = tokio::spawn(async move {
-        while let Ok(chat_msg) = broadcast_rx.recv().await {
-            if chat_msg.sender != client_name_clone {
-                if let Ok(json) = serde_json::to_string(&chat_msg) {
-                    let mut session = noise_session_recv.lock().await;
-                    if let Ok(encrypted) = session.encrypt(json.as_bytes()) {
-                        let mut sender = ws_sender_broadcast.lock().await;
-                        if sender.send(Message::Binary(encrypted)).await.is_err() {
-                            break;
-                        }
-                    }
-                }
+    /// Adds or removes a name, rewriting BANNED_NAMES_FILE if one is set.
+    /// Returns false if the list already had that state.
+    fn set(&mut self, name: &str, banned: bool) -> io::Result<bool> {
+        let name = Self::normalize(name);
+        let changed = if banned { self.names.insert(name) } else { self.names.remove(&name) };
+        if changed {
+            if let Some(path) = BANNED_NAMES_FILE {
+                let mut names: Vec<&String> = self.names.iter().collect();
+                names.sort();
+                let contents: String = names.iter().map(|name| format!("{}\n", name)).collect();
+                std::fs::write(path, contents)?;
             }
         }
-    });
+        Ok(changed)
+    }
+}
 
-    // Server commands to this client
-    let server_cmd_task = tokio::spawn(async move {
-        while let Ok(cmd) = server_cmd_rx.recv().await {
-            // Check if message is for this client or broadcast
-            let should_send = match &cmd.target {
-                None => true,  // Broadcast to all
-                Some(target_name) => target_name == &client_name_server,  // Targeted message
-            };
+// Recent handshake failures and active bans per source IP, for HANDSHAKE_FAILURE_LIMIT
+#[derive(Default)]
+struct HandshakeFailures {
+    recent: HashMap<IpAddr, VecDeque<Instant>>,
+    banned_until: HashMap<IpAddr, Instant>,
+}
 
-            if should_send {
-                if let Ok(json) = serde_json::to_string(&cmd.message) {
-                    let mut session = noise_session_server.lock().await;
-                    if let Ok(encrypted) = session.encrypt(json.as_bytes()) {
-                        let mut sender = ws_sender_server.lock().await;
-                        if sender.send(Message::Binary(encrypted)).await.is_err() {
-                            break;
-                        }
-                    }
-                }
-            }
+impl HandshakeFailures {
+    /// Time left on the IP's ban, if it has one.
+    fn ban_remaining(&mut self, ip: IpAddr) -> Option<Duration> {
+        let until = *self.banned_until.get(&ip)?;
+        let now = Instant::now();
+        if until <= now {
+            self.banned_until.remove(&ip);
+            return None;
         }
-    });
+        Some(until - now)
+    }
 
-    // Receive messages from this client
-    let noise_session_send = Arc::clone(&noise_session);
-    let broadcast_tx_clone = broadcast_tx.clone();
-    let client_name_send = client_name.clone();
-    
-    let receive_task = tokio::spawn(async move {
-        while let Some(msg) = ws_receiver.next().await {
-            match msg {
-                Ok(Message::Binary(encrypted_data)) => {
-                    let mut session = noise_session_send.lock().await;
-                    match session.decrypt(&encrypted_data) {
-                        Ok(decrypted) => {
-                            if let Ok(json_str) = String::from_utf8(decrypted) {
-                                if let Ok(mut chat_msg) = serde_json::from_str::<ChatMessage>(&json_str) {
-                                    chat_msg.sender = client_name_send.clone();
-                                    println!("{}: {}", chat_msg.sender, chat_msg.content);
-                                    let _ = broadcast_tx_clone.send(chat_msg);
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Decryption failed: {}", e);
-                        }
-                    }
-                }
-                Ok(Message::Close(_)) => {
-                    println!("{} disconnected", client_name_send);
-                    break;
-                }
-                _ => {}
-            }
+    /// Records a failure, returning true if it got the IP banned.
+    fn record(&mut self, ip: IpAddr) -> bool {
+        match HANDSHAKE_FAILURE_LIMIT {
+            Some(limit) => self.record_against(ip, limit),
+            None => false,
         }
-    });
+    }
 
-    tokio::select! {
-        _ = broadcast_task => {}
-        _ = server_cmd_task => {}
-        _ = receive_task => {}
+    fn record_against(&mut self, ip: IpAddr, limit: usize) -> bool {
+        let now = Instant::now();
+        let window = Duration::from_secs(HANDSHAKE_FAILURE_WINDOW_SECS);
+        // Forget IPs whose failures have all aged out, so the map stays small
+        self.recent
+            .retain(|_, failures| failures.back().is_some_and(|&at| now.duration_since(at) < window));
+        let failures = self.recent.entry(ip).or_default();
+        while failures.front().is_some_and(|&at| now.duration_since(at) >= window) {
+            failures.pop_front();
+        }
+        failures.push_back(now);
+        if failures.len() < limit {
+            return false;
+        }
+        self.recent.remove(&ip);
+        self.banned_until.insert(ip, now + Duration::from_secs(HANDSHAKE_BAN_SECS));
+        true
     }
+}
 
-    clients.lock().await.remove(&client_id);
-    let leave_msg = ChatMessage {
-        sender: "Server".to_string(),
-        content: format!("{} left the chat", client_name),
-    };
-    let _ = broadcast_tx.send(leave_msg);
+// Sliding window of recent inbound frame sizes, for CLIENT_BYTE_QUOTA. Timed
+// with tokio's Instant, so tests can pause and advance the clock
+struct ByteQuota {
+    limit: u64,
+    window: Duration,
+    frames: VecDeque<(Instant, u64)>,
+    total: u64,
 }
 
-async fn perform_noise_handshake_responder(
-    ws_sender: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<TcpStream>, Message>,
-    ws_receiver: &mut futures_util::stream::SplitStream<tokio_tungstenite::WebSocketStream<TcpStream>>,
-) -> Result<NoiseSession, Box<dyn std::error::Error>> {
-    let mut handshake = create_responder()?;
-    let mut buf = vec![0u8; 65535];
+impl ByteQuota {
+    fn new(limit: u64, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            frames: VecDeque::new(),
+            total: 0,
+        }
+    }
 
-    if let Some(msg) = ws_receiver.next().await {
-        match msg? {
-            Message::Binary(data) => {
-                handshake.read_message(&data, &mut buf)?;
-                let len = handshake.write_message(&[], &mut buf)?;
-                ws_sender.send(Message::Binary(buf[..len].to_vec())).await?;
+    /// Records a frame and returns how long to wait until the client is back
+    /// under its quota, or None if it already is.
+    fn record(&mut self, bytes: u64) -> Option<Duration> {
+        let now = Instant::now();
+        while let Some(&(at, size)) = self.frames.front() {
+            if now.duration_since(at) < self.window {
+                break;
+            }
+            self.frames.pop_front();
+            self.total -= size;
+        }
+        self.frames.push_back((now, bytes));
+        self.total += bytes;
 
-                if let Some(msg) = ws_receiver.next().await {
-                    match msg? {
-                        Message::Binary(data) => {
-                            handshake.read_message(&data, &mut buf)?;
-                            let transport = handshake.into_transport_mode()?;
-                            Ok(NoiseSession::new(transport))
-                        }
-                        _ => Err("Expected binary message".into()),
-                    }
-                } else {
-                    Err("Connection closed".into())
-                }
+        if self.total <= self.limit {
+            return None;
+        }
+        // Wait until enough of the oldest frames have aged out
+        let mut excess = self.total - self.limit;
+        for &(at, size) in &self.frames {
+            if size >= excess {
+                return Some(self.window.saturating_sub(now.duration_since(at)));
             }
-            _ => Err("Expected binary message".into()),
+            excess -= size;
         }
-    } else {
-        Err("Connection closed".into())
+        Some(self.window)
     }
-} 
\ No newline at end of file
+}
+
+enum FilterAction {
+    Allow,
+    Drop(Option<String>),  // Some(reason) is sent back to the sender
+    Modify(ChatMessage),
+}
+
+/// Inspects each client message before it is broadcast.
+trait MessageFilter: Send + Sync {
+    fn filter(&self, msg: &ChatMessage) -> FilterAction;
+}
+
+struct NoFilter;
+
+impl MessageFilter for NoFilter {
+    fn filter(&self, _msg: &ChatMessage) -> FilterAction {
+        FilterAction::Allow
+    }
+}
+
+struct WordListFilter {
+    words: Vec<String>,
+    drop: bool,
+}
+
+impl WordListFilter {
+    fn new(words: &[&str], drop: bool) -> Self {
+        Self {
+            words: words.iter().map(|w| w.to_lowercase()).collect(),
+            drop,
+        }
+    }
+}
+
+impl MessageFilter for WordListFilter {
+    fn filter(&self, msg: &ChatMessage) -> FilterAction {
+        let mut blocked = false;
+        let masked: Vec<String> = msg
+            .content
+            .split(' ')
+            .map(|word| {
+                let bare = word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase();
+                if self.words.contains(&bare) {
+                    blocked = true;
+                    "*".repeat(word.chars().count())
+                } else {
+                    word.to_string()
+                }
+            })
+            .collect();
+
+        if !blocked {
+            FilterAction::Allow
+        } else if self.drop {
+            FilterAction::Drop(Some("message contains a blocked word".to_string()))
+        } else {
+            FilterAction::Modify(ChatMessage {
+                content: masked.join(" "),
+                ..msg.clone()
+            })
+        }
+    }
+}
+
+/// Decides whether a remote admin request may run, or an observer may attach.
+/// The local console is trusted implicitly and never goes through this.
+trait AdminAuth: Send + Sync {
+    fn authenticate(&self, credential: &str) -> bool;
+}
+
+// Refuses every credential; used when ADMIN_TOKEN or OBSERVER_TOKEN is unset
+struct DenyAll;
+
+impl AdminAuth for DenyAll {
+    fn authenticate(&self, _credential: &str) -> bool {
+        false
+    }
+}
+
+struct TokenAuth {
+    digest: [u8; 32],
+}
+
+impl TokenAuth {
+    fn new(token: &str) -> Self {
+        Self {
+            digest: Sha256::digest(token.as_bytes()).into(),
+        }
+    }
+}
+
+impl AdminAuth for TokenAuth {
+    fn authenticate(&self, credential: &str) -> bool {
+        // Comparing digests keeps the time taken independent of the token
+        let digest: [u8; 32] = Sha256::digest(credential.as_bytes()).into();
+        digest.iter().zip(self.digest.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
+
+enum AdminVerdict {
+    Authorized,
+    Rejected,
+    // The connection has used up ADMIN_ATTEMPT_LIMIT and should be closed
+    Disconnect,
+}
+
+// Wrong admin tokens sent over one connection
+#[derive(Default)]
+struct AdminAttempts {
+    failed: u32,
+}
+
+impl AdminAttempts {
+    fn check(&mut self, auth: &dyn AdminAuth, token: &str) -> AdminVerdict {
+        self.check_against(auth, token, ADMIN_ATTEMPT_LIMIT)
+    }
+
+    fn check_against(&mut self, auth: &dyn AdminAuth, token: &str, limit: u32) -> AdminVerdict {
+        if auth.authenticate(token) {
+            return AdminVerdict::Authorized;
+        }
+        self.failed += 1;
+        if self.failed >= limit {
+            AdminVerdict::Disconnect
+        } else {
+            AdminVerdict::Rejected
+        }
+    }
+}
+
+// What console commands need, whether typed locally or sent by a remote admin
+#[derive(Clone)]
+struct AdminContext {
+    clients: Arc<Mutex<Roster>>,
+    stats: Arc<ServerStats>,
+    static_key: Arc<Mutex<Option<StaticKeypair>>>,
+    server_cmd_tx: broadcast::Sender<ServerCommand>,
+    draining: Arc<AtomicBool>,
+    name_bans: Arc<std::sync::Mutex<NameBans>>,
+}
+
+// What a connection that completed the name exchange turned out to be
+enum Joined {
+    Participant(String),
+    Observer,
+}
+
+// Why a connection never joined
+enum NotJoined {
+    // The upgrade or Noise handshake failed, or an observer token was wrong;
+    // counts toward HANDSHAKE_FAILURE_LIMIT
+    HandshakeFailed,
+    // The secure channel was up, but the name was refused or the client left
+    // at the prompt. Not counted, so a user retrying a bad name isn't banned
+    Refused,
+}
+
+type WsSender = futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<TcpStream>, Message>;
+type WsReceiver = futures_util::stream::SplitStream<tokio_tungstenite::WebSocketStream<TcpStream>>;
+
+// Shared by every listener and connection
+#[derive(Clone)]
+struct ServerState {
+    broadcast_tx: broadcast::Sender<ChatMessage>,
+    server_cmd_tx: broadcast::Sender<ServerCommand>,
+    clients: Arc<Mutex<Roster>>,
+    client_counter: Arc<Mutex<u32>>,
+    static_key: Arc<Mutex<Option<StaticKeypair>>>,
+    message_filter: Arc<dyn MessageFilter>,
+    next_message_id: Arc<AtomicU64>,
+    ip_connections: IpConnections,
+    handshake_slots: Arc<Semaphore>,
+    stats: Arc<ServerStats>,
+    handshake_failures: Arc<std::sync::Mutex<HandshakeFailures>>,
+    admin_auth: Arc<dyn AdminAuth>,
+    observer_auth: Arc<dyn AdminAuth>,
+    // Set by /drain: new connections are refused while existing ones finish
+    draining: Arc<AtomicBool>,
+    name_bans: Arc<std::sync::Mutex<NameBans>>,
+}
+
+#[derive(Debug, Clone)]
+struct ServerCommand {
+    target: Option<String>,  // None = broadcast, Some(name) = send to specific client
+    message: ChatMessage,
+}
+
+// Variants are named after the error they wrap
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+enum NoiseError {
+    HandshakeError(String),
+    EncryptionError(String),
+    DecryptionError(String),
+    SerializationError(String),
+}
+
+impl std::fmt::Display for NoiseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            NoiseError::HandshakeError(msg) => write!(f, "Handshake error: {}", msg),
+            NoiseError::EncryptionError(msg) => write!(f, "Encryption error: {}", msg),
+            NoiseError::DecryptionError(msg) => write!(f, "Decryption error: {}", msg),
+            NoiseError::SerializationError(msg) => write!(f, "Serialization error: {}", msg),
+        }
+    }
+}
+
+impl Error for NoiseError {}
+
+// Each side names SUBPROTOCOL in its encrypted handshake payload, binding the
+// protocol version to the session: a man in the middle who rewrites the
+// (unauthenticated) WebSocket header to force a downgrade breaks the handshake.
+fn check_bound_protocol(payload: &[u8]) -> Result<String, NoiseError> {
+    if payload == SUBPROTOCOL.as_bytes() {
+        Ok(SUBPROTOCOL.to_string())
+    } else {
+        Err(NoiseError::HandshakeError(format!(
+            "peer's handshake names protocol '{}', expected '{}'",
+            String::from_utf8_lossy(payload),
+            SUBPROTOCOL
+        )))
+    }
+}
+
+/// What a completed handshake actually negotiated, alongside the session itself
+struct HandshakeOutcome {
+    session: NoiseSession,
+    pattern: String,
+    psk_source: &'static str,
+    version: String,
+    remote_static_fp: Option<String>,
+}
+
+struct NoiseSession {
+    transport: TransportState,
+    handshake_hash: Vec<u8>,
+}
+
+impl NoiseSession {
+    fn new(transport: TransportState, handshake_hash: Vec<u8>) -> Self {
+        Self {
+            transport,
+            handshake_hash,
+        }
+    }
+
+    fn remote_static(&self) -> Option<&[u8]> {
+        self.transport.get_remote_static()
+    }
+
+    /// Short authentication string for comparing out of band. Both ends derive
+    /// it from the handshake hash, so a man in the middle can't make them match.
+    fn sas(&self) -> String {
+        let digest = Sha256::new()
+            .chain_update(b"secure-websocket sas")
+            .chain_update(&self.handshake_hash)
+            .finalize();
+        let code = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % 1_000_000;
+        format!("{:03} {:03}", code / 1000, code % 1000)
+    }
+
+    /// Serializes a chat message and encrypts it for sending. A message that
+    /// fails to serialize uses no nonce, so the session stays in step.
+    fn encrypt_message<T: Serialize>(&mut self, msg: &T) -> Result<Vec<u8>, NoiseError> {
+        let json = serde_json::to_vec(msg).map_err(|e| NoiseError::SerializationError(e.to_string()))?;
+        self.encrypt(&json)
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        let mut ciphertext = vec![0u8; plaintext.len() + TAG_LEN];
+        let len = self
+            .transport
+            .write_message(plaintext, &mut ciphertext)
+            .map_err(|e| NoiseError::EncryptionError(e.to_string()))?;
+        ciphertext.truncate(len);
+        Ok(ciphertext)
+    }
+
+    fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        let mut plaintext = vec![0u8; ciphertext.len()];
+        let len = self
+            .transport
+            .read_message(ciphertext, &mut plaintext)
+            .map_err(|e| NoiseError::DecryptionError(e.to_string()))?;
+        plaintext.truncate(len);
+        Ok(plaintext)
+    }
+
+    /// Describes a failed decrypt for the logs. Never includes plaintext or key material.
+    fn decrypt_failure_diagnostics(&self, ciphertext: &[u8]) -> String {
+        let hypothesis = if ciphertext.len() < TAG_LEN {
+            "frame shorter than the AEAD tag, likely truncated"
+        } else if ciphertext.len() > NOISE_MAX_MESSAGE_LEN {
+            "frame larger than any Noise message, likely not Noise ciphertext"
+        } else {
+            "authentication failed, likely cipher desync or wrong key; reconnect to rekey"
+        };
+        format!(
+            "ciphertext_len={} expected_nonce={} sent_nonce={} hypothesis=\"{}\"",
+            ciphertext.len(),
+            self.transport.receiving_nonce(),
+            self.transport.sending_nonce(),
+            hypothesis
+        )
+    }
+}
+
+#[derive(Clone)]
+struct StaticKeypair {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl StaticKeypair {
+    fn generate() -> Result<Self, NoiseError> {
+        let builder = Builder::new(noise_params()?);
+        let keypair = builder.generate_keypair().map_err(|e| NoiseError::HandshakeError(e.to_string()))?;
+        Ok(Self {
+            private: keypair.private,
+            public: keypair.public,
+        })
+    }
+
+    /// Loads the keypair stored at `path`, creating one if the file doesn't exist yet.
+    fn load_or_generate(path: &str) -> Result<Self, Box<dyn Error>> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                let mut lines = contents.lines();
+                let private = lines.next().and_then(from_hex);
+                let public = lines.next().and_then(from_hex);
+                match (private, public) {
+                    (Some(private), Some(public)) if private.len() == 32 && public.len() == 32 => {
+                        Ok(Self { private, public })
+                    }
+                    _ => Err(format!("Invalid static key file: {}", path).into()),
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::rotate(path),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Generates a new keypair and atomically replaces the one stored at `path`.
+    fn rotate(path: &str) -> Result<Self, Box<dyn Error>> {
+        let keypair = Self::generate()?;
+        let tmp_path = format!("{}.tmp", path);
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&tmp_path)?;
+        writeln!(file, "{}", to_hex(&keypair.private))?;
+        writeln!(file, "{}", to_hex(&keypair.public))?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(keypair)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Short, non-reversible identifier for secret material, safe to display
+fn fingerprint(secret: &[u8]) -> String {
+    let digest = Sha256::new()
+        .chain_update(b"secure-websocket fingerprint")
+        .chain_update(secret)
+        .finalize();
+    to_hex(&digest[..8])
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim();
+    if !s.is_ascii() || !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn noise_pattern() -> String {
+    match NOISE_PATTERN_OVERRIDE {
+        Some(pattern) => pattern.to_string(),
+        None => format!("Noise_{}_{}_{}_{}", HANDSHAKE_PATTERN, DH_FUNCTION, CIPHER, HASH),
+    }
+}
+
+/// Parses the configured protocol, naming the offending component when one
+/// isn't something snow (or this handshake code) supports.
+fn noise_params() -> Result<NoiseParams, NoiseError> {
+    parse_noise_params(&noise_pattern())
+}
+
+fn parse_noise_params(pattern: &str) -> Result<NoiseParams, NoiseError> {
+    let parts: Vec<&str> = pattern.split('_').collect();
+    let [_, handshake, dh, cipher, hash] = parts[..] else {
+        return Err(NoiseError::HandshakeError(format!(
+            "Noise protocol {} is not of the form Noise_<pattern>_<dh>_<cipher>_<hash>",
+            pattern
+        )));
+    };
+    let checks = [
+        ("handshake pattern", handshake, &["XXpsk2"][..]),
+        ("DH function", dh, &["25519"][..]),
+        ("cipher", cipher, &["AESGCM", "ChaChaPoly"][..]),
+        ("hash", hash, &["SHA256", "SHA512", "BLAKE2s", "BLAKE2b"][..]),
+    ];
+    for (component, value, supported) in checks {
+        if !supported.contains(&value) {
+            return Err(NoiseError::HandshakeError(format!(
+                "Unsupported {} '{}' in {}; supported: {}",
+                component,
+                value,
+                pattern,
+                supported.join(", ")
+            )));
+        }
+    }
+    pattern
+        .parse()
+        .map_err(|e| NoiseError::HandshakeError(format!("Invalid Noise protocol {}: {}", pattern, e)))
+}
+
+/// Names the components that differ between two Noise protocol names.
+fn describe_protocol_mismatch(theirs: &str, ours: &str) -> String {
+    let components = ["prefix", "handshake pattern (and PSK position)", "DH function", "cipher", "hash"];
+    let differences: Vec<String> = theirs
+        .split('_')
+        .zip(ours.split('_'))
+        .zip(components)
+        .filter(|((a, b), _)| a != b)
+        .map(|((a, b), component)| format!("{} {} vs {}", component, a, b))
+        .collect();
+    if differences.is_empty() {
+        format!("{} vs {}", theirs, ours)
+    } else {
+        differences.join(", ")
+    }
+}
+
+/// Upper bound on any handshake message for the Noise protocol: an ephemeral key,
+/// an encrypted static key and an encrypted payload. Errors if that can't fit
+/// in a Noise message, so a bad pattern fails at startup rather than mid-handshake.
+fn handshake_buffer_len() -> Result<usize, NoiseError> {
+    handshake_buffer_len_for(&noise_pattern(), MAX_HANDSHAKE_PAYLOAD_LEN)
+}
+
+fn handshake_buffer_len_for(pattern: &str, max_payload_len: usize) -> Result<usize, NoiseError> {
+    // noise_params accepts no other curve
+    let dh_len = match pattern.split('_').nth(2) {
+        Some("25519") => 32,
+        other => {
+            return Err(NoiseError::HandshakeError(format!(
+                "Unsupported DH function {:?} in {}",
+                other, pattern
+            )))
+        }
+    };
+    if SUBPROTOCOL.len() > max_payload_len {
+        return Err(NoiseError::HandshakeError(format!(
+            "Subprotocol name {} is longer than the {}-byte handshake payload limit",
+            SUBPROTOCOL, max_payload_len
+        )));
+    }
+    let len = dh_len + (dh_len + TAG_LEN) + (max_payload_len + TAG_LEN);
+    if len > NOISE_MAX_MESSAGE_LEN {
+        return Err(NoiseError::HandshakeError(format!(
+            "Handshake messages for {} can reach {} bytes, over the Noise limit of {}",
+            pattern, len, NOISE_MAX_MESSAGE_LEN
+        )));
+    }
+    Ok(len)
+}
+
+fn create_responder(static_key: Option<&StaticKeypair>) -> Result<HandshakeState, NoiseError> {
+    let builder = Builder::new(noise_params()?);
+    let keypair = match static_key {
+        Some(keypair) => keypair.clone(),
+        None => StaticKeypair::generate()?,
+    };
+
+    builder
+        .local_private_key(&keypair.private)
+        .psk(2, PSK)
+        .build_responder()
+        .map_err(|e| NoiseError::HandshakeError(e.to_string()))
+}
+
+#[tokio::main(flavor = "multi_thread")]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut listeners = Vec::new();
+    for addr in SERVER_ADDRESSES {
+        match TcpListener::bind(*addr).await {
+            Ok(listener) => {
+                println!("Server listening on: {}", addr);
+                listeners.push(listener);
+            }
+            Err(e) if BIND_FAIL_FAST => return Err(format!("Failed to bind {}: {}", addr, e).into()),
+            Err(e) => eprintln!("Failed to bind {}, skipping it: {}", addr, e),
+        }
+    }
+    if listeners.is_empty() {
+        return Err("No listen address could be bound".into());
+    }
+    println!("Using Noise protocol: {}", noise_pattern());
+    println!("WebSocket subprotocol: {}", SUBPROTOCOL);
+    noise_params()?;
+    handshake_buffer_len()?;
+
+    let static_key = match STATIC_KEY_FILE {
+        Some(path) => {
+            let keypair = StaticKeypair::load_or_generate(path)?;
+            println!("Static public key: {}", to_hex(&keypair.public));
+            Some(keypair)
+        }
+        None => None,
+    };
+    let static_key = Arc::new(Mutex::new(static_key));
+
+    let message_filter: Arc<dyn MessageFilter> = match BLOCKED_WORDS {
+        Some(words) => Arc::new(WordListFilter::new(words, DROP_BLOCKED_MESSAGES)),
+        None => Arc::new(NoFilter),
+    };
+    println!("Commands: '@ClientName message' to send to specific client, or 'message' to broadcast");
+
+    let (broadcast_tx, _) = broadcast::channel::<ChatMessage>(BROADCAST_CAPACITY);
+    let (server_cmd_tx, _) = broadcast::channel::<ServerCommand>(BROADCAST_CAPACITY);
+    let clients = Arc::new(Mutex::new(Roster::default()));
+    let client_counter = Arc::new(Mutex::new(0u32));
+    let stats = Arc::new(ServerStats::new());
+
+    let draining = Arc::new(AtomicBool::new(false));
+    let name_bans = match BANNED_NAMES_FILE {
+        Some(path) => {
+            let bans = NameBans::load(path)?;
+            println!("Loaded {} banned name(s) from {}", bans.names.len(), path);
+            bans
+        }
+        None => NameBans::default(),
+    };
+    let name_bans = Arc::new(std::sync::Mutex::new(name_bans));
+
+    // Server input task
+    let admin = AdminContext {
+        clients: clients.clone(),
+        stats: Arc::clone(&stats),
+        static_key: static_key.clone(),
+        server_cmd_tx: server_cmd_tx.clone(),
+        draining: Arc::clone(&draining),
+        name_bans: Arc::clone(&name_bans),
+    };
+    let admin_auth: Arc<dyn AdminAuth> = match ADMIN_TOKEN {
+        Some(token) => {
+            println!("Remote admin commands enabled");
+            Arc::new(TokenAuth::new(token))
+        }
+        None => Arc::new(DenyAll),
+    };
+    let observer_auth: Arc<dyn AdminAuth> = match OBSERVER_TOKEN {
+        Some(token) => {
+            println!("Observers enabled");
+            Arc::new(TokenAuth::new(token))
+        }
+        None => Arc::new(DenyAll),
+    };
+    tokio::spawn(run_console(BufReader::new(tokio::io::stdin()), admin));
+
+    let state = ServerState {
+        broadcast_tx,
+        server_cmd_tx,
+        clients,
+        client_counter,
+        static_key,
+        message_filter,
+        next_message_id: Arc::new(AtomicU64::new(1)),
+        ip_connections: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        handshake_slots: Arc::new(Semaphore::new(MAX_CONCURRENT_HANDSHAKES)),
+        stats,
+        handshake_failures: Arc::new(std::sync::Mutex::new(HandshakeFailures::default())),
+        admin_auth,
+        observer_auth,
+        draining,
+        name_bans,
+    };
+
+    // All listeners feed the same clients and broadcast channels
+    let accept_loops: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| tokio::spawn(accept_loop(listener, state.clone())))
+        .collect();
+    futures_util::future::join_all(accept_loops).await;
+    Ok(())
+}
+
+/// How the console input task stopped
+#[derive(Debug, PartialEq)]
+enum ConsoleEnd {
+    /// Input reached end of file, e.g. a pipe that ran dry or a closed terminal
+    Eof,
+    /// Reading input failed
+    Failed,
+}
+
+/// Runs the console commands and messages read from `input` until it ends.
+/// Only the console stops; connected clients are unaffected.
+async fn run_console<R: AsyncBufRead + Unpin>(input: R, admin: AdminContext) -> ConsoleEnd {
+    let mut lines = input.lines();
+
+    print!("> ");
+    io::stdout().flush().unwrap();
+
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => {
+                println!("Console input closed; the server keeps running without console commands");
+                return ConsoleEnd::Eof;
+            }
+            Err(e) => {
+                eprintln!("Failed to read console input: {}", e);
+                return ConsoleEnd::Failed;
+            }
+        };
+        let line = line.trim();
+
+        if line.is_empty() {
+            print!("> ");
+            io::stdout().flush().unwrap();
+            continue;
+        }
+
+        if let Some(output) = run_admin_command(line, &admin).await {
+            println!("{}", output);
+            print!("> ");
+            io::stdout().flush().unwrap();
+            continue;
+        }
+
+        let (target, content) = if line.starts_with('@') {
+            // Targeted message: @ClientName message
+            if let Some(space_pos) = line.find(' ') {
+                let name = &line[1..space_pos];
+                let msg = &line[space_pos + 1..];
+                (Some(name.to_string()), msg.to_string())
+            } else {
+                println!("Invalid format. Use: @ClientName message");
+                print!("> ");
+                io::stdout().flush().unwrap();
+                continue;
+            }
+        } else {
+            // Broadcast message; "//" sends a leading "/"
+            (None, line.strip_prefix('/').unwrap_or(line).to_string())
+        };
+
+        let cmd = ServerCommand {
+            target: target.clone(),
+            message: ChatMessage {
+                sender: "Server".to_string(),
+                content: content.clone(),
+                control: None,
+                rich: None,
+                meta: None,
+            },
+        };
+
+        if let Some(name) = &target {
+            let holder = admin.clients.lock().await.holder(name);
+            if let Err(reason) = holder.direct_target(name) {
+                println!("{}", reason);
+                print!("> ");
+                io::stdout().flush().unwrap();
+                continue;
+            }
+            println!("To {}: {}", name, content);
+        } else {
+            println!("Broadcast: {}", content);
+        }
+
+        let _ = admin.server_cmd_tx.send(cmd);
+        print!("> ");
+        io::stdout().flush().unwrap();
+    }
+}
+
+/// Runs a console command and returns what to show, or None if the line is
+/// not one (so the console treats it as a message).
+async fn run_admin_command(line: &str, admin: &AdminContext) -> Option<String> {
+    let mut out = Vec::new();
+    if line == "/rotate-key" {
+        match STATIC_KEY_FILE {
+            // The boxed error isn't Send, so it must not be held across the await below
+            Some(path) => match StaticKeypair::rotate(path).map_err(|e| e.to_string()) {
+                Ok(keypair) => {
+                    out.push(format!("Static key rotated. New public key: {}", to_hex(&keypair.public)));
+                    *admin.static_key.lock().await = Some(keypair);
+                }
+                Err(e) => out.push(format!("Failed to rotate static key: {}", e)),
+            },
+            None => out.push("No persistent static key configured (STATIC_KEY_FILE)".to_string()),
+        }
+    } else if line == "/stats" {
+        let stats = &admin.stats;
+        let online = admin.clients.lock().await.clients.values().filter(|c| c.connected).count();
+        let uptime = stats.started.elapsed().as_secs();
+        out.push(format!("Uptime:            {}h {:02}m {:02}s", uptime / 3600, uptime / 60 % 60, uptime % 60));
+        out.push(format!("Clients online:    {}", online));
+        out.push(format!("Clients joined:    {}", stats.joined.load(Ordering::Relaxed)));
+        out.push(format!(
+            "Failed handshakes: {} ({} timed out)",
+            stats.failed_handshakes.load(Ordering::Relaxed) + stats.handshake_timeouts.load(Ordering::Relaxed),
+            stats.handshake_timeouts.load(Ordering::Relaxed)
+        ));
+        out.push(format!("Messages relayed:  {}", stats.messages_relayed.load(Ordering::Relaxed)));
+        out.push(format!("IP bans issued:    {}", stats.bans.load(Ordering::Relaxed)));
+        if admin.draining.load(Ordering::Relaxed) {
+            out.push("Draining:          refusing new connections".to_string());
+        }
+    } else if let Some(name) = line.strip_prefix("/verify ") {
+        let name = name.trim();
+        match admin.clients.lock().await.clients.values().find(|c| c.name == name) {
+            Some(client) => out.push(format!("Verification code for {}: {}", client.name, client.sas)),
+            None => out.push(format!("No client named '{}'", name)),
+        }
+    } else if let Some(url) = line.strip_prefix("/redirect ") {
+        let url = url.trim();
+        if url.starts_with("ws://") || url.starts_with("wss://") {
+            let _ = admin.server_cmd_tx.send(ServerCommand {
+                target: None,
+                message: ChatMessage {
+                    sender: "Server".to_string(),
+                    content: format!("Moving to {}", url),
+                    control: Some(ControlMessage::Redirect { url: url.to_string() }),
+                    rich: None,
+                    meta: None,
+                },
+            });
+            out.push(format!("Redirected connected clients to {}", url));
+        } else {
+            out.push("Usage: /redirect ws://host:port".to_string());
+        }
+    } else if let Some(text) = line.strip_prefix("/sys ") {
+        let text = text.trim();
+        let _ = admin.server_cmd_tx.send(ServerCommand {
+            target: None,
+            message: ChatMessage {
+                sender: String::new(),
+                content: text.to_string(),
+                control: Some(ControlMessage::System),
+                rich: None,
+                meta: None,
+            },
+        });
+        out.push(format!("System notice: {}", text));
+    } else if line == "/drain" {
+        if admin.draining.swap(true, Ordering::Relaxed) {
+            out.push("Already draining".to_string());
+        } else {
+            out.push(format!(
+                "Draining: refusing new connections; exiting once all clients leave or after {} s (/ready cancels)",
+                DRAIN_TIMEOUT_SECS
+            ));
+            tokio::spawn(drain(admin.clone()));
+        }
+    } else if line == "/ready" {
+        if admin.draining.swap(false, Ordering::Relaxed) {
+            out.push("Accepting new connections again".to_string());
+        } else {
+            out.push("Not draining".to_string());
+        }
+    } else if let Some(name) = line.strip_prefix("/ban ") {
+        let name = name.trim();
+        match admin.name_bans.lock().unwrap().set(name, true) {
+            Ok(true) => out.push(format!("Banned '{}'", name)),
+            Ok(false) => out.push(format!("'{}' is already banned", name)),
+            Err(e) => out.push(format!("Banned '{}' for now, but failed to save the ban list: {}", name, e)),
+        }
+        if let Some(client) = admin.clients.lock().await.clients.values().find(|c| NameBans::normalize(&c.name) == NameBans::normalize(name)) {
+            let _ = admin.server_cmd_tx.send(ServerCommand {
+                target: Some(client.name.clone()),
+                message: disconnect_notice(DisconnectReason::Banned, "Disconnected: you have been banned".to_string()),
+            });
+            out.push(format!("Disconnecting {}", client.name));
+        }
+    } else if let Some(name) = line.strip_prefix("/unban ") {
+        let name = name.trim();
+        match admin.name_bans.lock().unwrap().set(name, false) {
+            Ok(true) => out.push(format!("Unbanned '{}'", name)),
+            Ok(false) => out.push(format!("'{}' is not banned", name)),
+            Err(e) => out.push(format!("Unbanned '{}' for now, but failed to save the ban list: {}", name, e)),
+        }
+    } else if line == "/reload-bans" {
+        match BANNED_NAMES_FILE {
+            Some(path) => match NameBans::load(path) {
+                Ok(bans) => {
+                    out.push(format!("Loaded {} banned name(s) from {}", bans.names.len(), path));
+                    *admin.name_bans.lock().unwrap() = bans;
+                }
+                Err(e) => out.push(format!("Failed to read {}: {}", path, e)),
+            },
+            None => out.push("No ban list file configured (BANNED_NAMES_FILE)".to_string()),
+        }
+    } else if line == "/list" {
+        let roster = admin.clients.lock().await;
+        let mut clients: Vec<&ClientInfo> = roster.clients.values().collect();
+        clients.sort_by(|a, b| a.name.cmp(&b.name));
+        let away = clients.iter().filter(|c| !c.connected).count();
+        out.push(format!("{} client(s) connected, {} away", clients.len() - away, away));
+        for client in clients {
+            out.push(format!(
+                "  {} ({}){} in: {} bytes, out: {} bytes, slow sends: {}, rtt: {}",
+                client.name,
+                client.addr,
+                if client.connected { "" } else { " away," },
+                client.stats.bytes_in.load(Ordering::Relaxed),
+                client.stats.bytes_out.load(Ordering::Relaxed),
+                client.stats.slow_sends.load(Ordering::Relaxed),
+                client.stats.rtt_ms().map_or("-".to_string(), |ms| format!("{:.1} ms", ms))
+            ));
+        }
+    } else if let Some(command) = line.strip_prefix('/').filter(|_| !line.starts_with("//")) {
+        // Never let a mistyped command go out to everyone as chat
+        let command = command.split_whitespace().next().unwrap_or_default();
+        match command {
+            "verify" | "ban" | "unban" => out.push(format!("Usage: /{} <name>", command)),
+            "sys" => out.push("Usage: /sys <message>".to_string()),
+            "redirect" => out.push("Usage: /redirect ws://host:port".to_string()),
+            _ => out.push(format!(
+                "Unknown command '/{}'. Commands: /stats, /list, /rotate-key, /verify, /drain, /ready, /ban, /unban, /reload-bans, /sys, /redirect",
+                command
+            )),
+        }
+    } else {
+        return None;
+    }
+    Some(out.join("\n"))
+}
+
+// How a drain ended
+#[derive(Debug, PartialEq)]
+enum DrainEnd {
+    // /ready put the server back in service
+    Cancelled,
+    // Every client left on its own
+    Drained,
+    // The timeout passed and the remaining clients were sent a shutdown notice
+    TimedOut,
+}
+
+/// Exits once the drain started by /drain ends, unless /ready cancels it.
+async fn drain(admin: AdminContext) {
+    if drain_within(&admin, Duration::from_secs(DRAIN_TIMEOUT_SECS)).await != DrainEnd::Cancelled {
+        println!("Shutting down");
+        std::process::exit(0);
+    }
+}
+
+/// Waits for connected clients to leave; after `limit` the rest are sent a
+/// shutdown notice and given SHUTDOWN_NOTICE_SECS to go.
+async fn drain_within(admin: &AdminContext, limit: Duration) -> DrainEnd {
+    let deadline = Instant::now() + limit;
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        if !admin.draining.load(Ordering::Relaxed) {
+            return DrainEnd::Cancelled;
+        }
+        // Grace-period entries (RECONNECT_GRACE_SECS) have no connection to wait for
+        let remaining = connected_clients(&admin.clients).await;
+        if remaining == 0 {
+            println!("Drained: no clients left");
+            return DrainEnd::Drained;
+        }
+        if Instant::now() >= deadline {
+            println!("Drain timeout elapsed with {} client(s) still connected, disconnecting them", remaining);
+            break;
+        }
+    }
+
+    // Each connection closes itself once the notice is sent, which takes it out of the count
+    let _ = admin.server_cmd_tx.send(ServerCommand {
+        target: None,
+        message: disconnect_notice(DisconnectReason::Shutdown, "Server is shutting down".to_string()),
+    });
+    let deadline = Instant::now() + Duration::from_secs(SHUTDOWN_NOTICE_SECS);
+    while Instant::now() < deadline && connected_clients(&admin.clients).await > 0 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    DrainEnd::TimedOut
+}
+
+async fn connected_clients(clients: &Mutex<Roster>) -> usize {
+    clients.lock().await.clients.values().filter(|c| c.connected).count()
+}
+
+async fn accept_loop(listener: TcpListener, state: ServerState) {
+    loop {
+        if let Ok((stream, addr)) = listener.accept().await {
+            if state.draining.load(Ordering::Relaxed) {
+                println!("Refused connection from {}: server is draining", addr);
+                tokio::spawn(async move {
+                    let mut stream = stream;
+                    refuse(&mut stream, "503 Service Unavailable", 5).await;
+                });
+                continue;
+            }
+            let banned = state.handshake_failures.lock().unwrap().ban_remaining(addr.ip());
+            if let Some(remaining) = banned {
+                println!("Refused connection from {}: banned after repeated handshake failures", addr);
+                tokio::spawn(async move {
+                    let mut stream = stream;
+                    refuse(&mut stream, "403 Forbidden", remaining.as_secs() + 1).await;
+                });
+                continue;
+            }
+            let Some(slot) = IpSlot::acquire(&state.ip_connections, addr.ip()) else {
+                println!("Refused connection from {}: too many connections from this IP", addr);
+                tokio::spawn(async move {
+                    let mut stream = stream;
+                    refuse(&mut stream, "503 Service Unavailable", 5).await;
+                });
+                continue;
+            };
+            println!("New connection from: {}", addr);
+            if let Err(err) = tune_socket(&stream) {
+                eprintln!("Failed to set socket options for {}: {}", addr, err);
+            }
+            let state = state.clone();
+            
+            tokio::spawn(async move {
+                let _slot = slot;
+                handle_connection(stream, addr, state).await;
+            });
+        }
+    }
+}
+
+/// Applies TCP_NODELAY, TCP_KEEPALIVE_SECS and SOCKET_BUFFER_SIZE to a connection.
+fn tune_socket(stream: &TcpStream) -> io::Result<()> {
+    stream.set_nodelay(TCP_NODELAY)?;
+    let socket = SockRef::from(stream);
+    if let Some(secs) = TCP_KEEPALIVE_SECS {
+        socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(Duration::from_secs(secs)))?;
+    }
+    if let Some(size) = SOCKET_BUFFER_SIZE {
+        socket.set_recv_buffer_size(size)?;
+        socket.set_send_buffer_size(size)?;
+    }
+    Ok(())
+}
+
+/// Answers a connection that won't be upgraded with a bare HTTP error.
+async fn refuse(stream: &mut TcpStream, status: &str, retry_after_secs: u64) {
+    let response = format!(
+        "HTTP/1.1 {}\r\nRetry-After: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status, retry_after_secs
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+fn record_handshake_failure(failures: &std::sync::Mutex<HandshakeFailures>, stats: &ServerStats, addr: SocketAddr) {
+    if failures.lock().unwrap().record(addr.ip()) {
+        stats.bans.fetch_add(1, Ordering::Relaxed);
+        eprintln!(
+            "Security: banning {} for {}s after {} failed handshakes within {}s",
+            addr.ip(),
+            HANDSHAKE_BAN_SECS,
+            HANDSHAKE_FAILURE_LIMIT.unwrap_or_default(),
+            HANDSHAKE_FAILURE_WINDOW_SECS
+        );
+    }
+}
+
+// Server notices waiting for a client's socket. The broadcast task checks
+// between frames and steps aside while any are pending, so a notice isn't
+// stuck behind a batch of chat
+#[derive(Default)]
+struct NoticePriority {
+    pending: AtomicUsize,
+    // Signalled when the last pending notice has been sent
+    sent: Notify,
+}
+
+// A notice waiting for the socket; broadcasts resume once it is dropped
+struct PendingNotice<'a>(&'a NoticePriority);
+
+impl NoticePriority {
+    fn announce(&self) -> PendingNotice<'_> {
+        self.pending.fetch_add(1, Ordering::AcqRel);
+        PendingNotice(self)
+    }
+
+    fn any_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire) > 0
+    }
+
+    async fn wait_until_sent(&self) {
+        loop {
+            // Created before the check so a notification in between isn't missed
+            let sent = self.sent.notified();
+            if !self.any_pending() {
+                return;
+            }
+            sent.await;
+        }
+    }
+}
+
+impl Drop for PendingNotice<'_> {
+    fn drop(&mut self) {
+        if self.0.pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.sent.notify_waiters();
+        }
+    }
+}
+
+/// Encrypts and writes a batch of broadcast frames in order, stepping aside
+/// between frames for any pending server notice. `flush_each_frame`
+/// (FLUSH_EACH_FRAME) flushes every frame, otherwise the batch is flushed
+/// once at the end. False if the socket is gone.
+async fn write_broadcasts<S>(
+    frames: Vec<Vec<u8>>,
+    noise_session: &Mutex<NoiseSession>,
+    ws_sender: &Mutex<S>,
+    notices: &NoticePriority,
+    stats: &ConnectionStats,
+    flush_each_frame: bool,
+) -> bool
+where
+    S: Sink<Message> + Unpin,
+{
+    let mut session = noise_session.lock().await;
+    let mut sender = ws_sender.lock().await;
+    for plaintext in frames {
+        // Chat frames keep their order; a pending notice just goes first
+        if notices.any_pending() {
+            drop(sender);
+            drop(session);
+            notices.wait_until_sent().await;
+            session = noise_session.lock().await;
+            sender = ws_sender.lock().await;
+        }
+        if let Ok(encrypted) = session.encrypt(&plaintext) {
+            stats.bytes_out.fetch_add(encrypted.len() as u64, Ordering::Relaxed);
+            let frame = Message::Binary(encrypted);
+            let written = if flush_each_frame { sender.send(frame).await } else { sender.feed(frame).await };
+            if written.is_err() {
+                return false;
+            }
+        }
+    }
+    flush_each_frame || sender.flush().await.is_ok()
+}
+
+/// Packs serialized messages into as few plaintexts as fit in one Noise message each.
+/// A batch is BATCH_MARKER followed by (u32 big-endian length, message) pairs, in order.
+fn pack_batch(messages: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    if messages.len() <= 1 {
+        return messages;
+    }
+
+    let mut frames = Vec::new();
+    let mut current = vec![BATCH_MARKER];
+    for message in messages {
+        if 1 + 4 + message.len() > MAX_PLAINTEXT_LEN {
+            // Too large to share a frame; send it on its own without reordering
+            if current.len() > 1 {
+                frames.push(std::mem::replace(&mut current, vec![BATCH_MARKER]));
+            }
+            frames.push(message);
+            continue;
+        }
+        if current.len() + 4 + message.len() > MAX_PLAINTEXT_LEN {
+            frames.push(std::mem::replace(&mut current, vec![BATCH_MARKER]));
+        }
+        current.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        current.extend_from_slice(&message);
+    }
+    if current.len() > 1 {
+        frames.push(current);
+    }
+    frames
+}
+
+// Tungstenite reassembles fragmented (continuation) frames before yielding a
+// Message, so every Binary we read is one complete Noise message. Capping the
+// reassembled size at the Noise limit rejects anything larger up front,
+// instead of buffering it and failing in decrypt.
+fn websocket_config() -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(NOISE_MAX_MESSAGE_LEN),
+        max_frame_size: Some(NOISE_MAX_MESSAGE_LEN),
+        ..Default::default()
+    }
+}
+
+/// Refuses upgrades from browser pages whose origin is not in ALLOWED_ORIGINS,
+/// before any Noise traffic is exchanged.
+// ErrorResponse is what the upgrade callback must return
+#[allow(clippy::result_large_err)]
+fn check_origin(request: &Request) -> Result<(), ErrorResponse> {
+    check_origin_against(request, ALLOWED_ORIGINS)
+}
+
+#[allow(clippy::result_large_err)]
+fn check_origin_against(request: &Request, allowed: Option<&[&str]>) -> Result<(), ErrorResponse> {
+    let (Some(allowed), Some(origin)) = (allowed, request.headers().get(ORIGIN)) else {
+        return Ok(());
+    };
+    let origin = origin.to_str().unwrap_or("");
+    if allowed.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)) {
+        return Ok(());
+    }
+    println!("Security: refused upgrade from origin '{}'", origin);
+    let mut error = ErrorResponse::new(Some("Origin not allowed".to_string()));
+    *error.status_mut() = StatusCode::FORBIDDEN;
+    Err(error)
+}
+
+/// Compares the client's advertised Noise protocol with ours before any
+/// handshake message is read, refusing the upgrade with the differing
+/// components. Clients that don't send the header are let through.
+#[allow(clippy::result_large_err)]
+fn check_noise_protocol(request: &Request) -> Result<(), ErrorResponse> {
+    let Some(theirs) = request.headers().get(NOISE_PROTOCOL_HEADER) else {
+        return Ok(());
+    };
+    let ours = noise_pattern();
+    // Header values are visible ASCII when readable at all
+    let theirs: String = theirs.to_str().unwrap_or("(unreadable)").chars().take(MAX_HANDSHAKE_PAYLOAD_LEN).collect();
+    if theirs == ours {
+        return Ok(());
+    }
+    let mismatch = describe_protocol_mismatch(&theirs, &ours);
+    println!("Refused upgrade: client uses {}, this server {} ({})", theirs, ours, mismatch);
+    let mut error = ErrorResponse::new(Some(format!("Noise protocol mismatch: {}", mismatch)));
+    *error.status_mut() = StatusCode::BAD_REQUEST;
+    Err(error)
+}
+
+// Only accept clients that offer our subprotocol, and echo it back. The error
+// type is fixed by tungstenite's upgrade callback
+#[allow(clippy::result_large_err)]
+fn negotiate_subprotocol(request: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+    check_origin(request)?;
+    check_noise_protocol(request)?;
+    let offered = request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim() == SUBPROTOCOL);
+
+    if !offered {
+        let mut error = ErrorResponse::new(Some(format!("Unsupported subprotocol, expected {}", SUBPROTOCOL)));
+        *error.status_mut() = StatusCode::BAD_REQUEST;
+        return Err(error);
+    }
+
+    response
+        .headers_mut()
+        .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(SUBPROTOCOL));
+    Ok(response)
+}
+
+async fn handle_connection(mut stream: TcpStream, addr: SocketAddr, state: ServerState) {
+    let ServerState {
+        broadcast_tx,
+        server_cmd_tx,
+        clients,
+        client_counter,
+        static_key,
+        message_filter,
+        next_message_id,
+        ip_connections: _,
+        handshake_slots,
+        stats: server_stats,
+        handshake_failures,
+        admin_auth,
+        observer_auth,
+        draining,
+        name_bans,
+    } = state;
+
+    let handshake_slot = match timeout(Duration::from_secs(HANDSHAKE_QUEUE_SECS), handshake_slots.acquire_owned()).await {
+        Ok(Ok(permit)) => permit,
+        _ => {
+            println!("Refusing connection from {}: too many handshakes in progress", addr);
+            refuse(&mut stream, "503 Service Unavailable", HANDSHAKE_QUEUE_SECS).await;
+            return;
+        }
+    };
+
+    // Rotation only affects handshakes that start after it
+    let local_key = static_key.lock().await.clone();
+    // Covers the upgrade, the Noise handshake and the name exchange, so a peer
+    // that stalls anywhere before joining can't hold its task open
+    let (mut ws_sender, mut ws_receiver, noise_session, joined) =
+        match timeout(
+            Duration::from_secs(HANDSHAKE_TIMEOUT_SECS),
+            accept_client(stream, local_key, &name_bans, observer_auth.as_ref()),
+        )
+        .await
+        {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(NotJoined::HandshakeFailed)) => {
+                server_stats.failed_handshakes.fetch_add(1, Ordering::Relaxed);
+                record_handshake_failure(&handshake_failures, &server_stats, addr);
+                return;
+            }
+            Ok(Err(NotJoined::Refused)) => return,
+            Err(_) => {
+                println!("Connection from {} timed out before joining", addr);
+                server_stats.handshake_timeouts.fetch_add(1, Ordering::Relaxed);
+                record_handshake_failure(&handshake_failures, &server_stats, addr);
+                return;
+            }
+        };
+    drop(handshake_slot);
+    let client_name = match joined {
+        Joined::Participant(name) => name,
+        Joined::Observer => {
+            println!("Observer attached from {}", addr);
+            observe(ws_sender, ws_receiver, noise_session, &clients, &broadcast_tx, &server_cmd_tx).await;
+            println!("Observer from {} detached", addr);
+            return;
+        }
+    };
+    let sas = noise_session.sas();
+    let noise_session = Arc::new(Mutex::new(noise_session));
+
+    let client_id = {
+        let mut counter = client_counter.lock().await;
+        *counter += 1;
+        *counter
+    };
+
+    let stats = Arc::new(ConnectionStats::default());
+
+    // Subscribe and snapshot under the same lock as the insert, so this client
+    // sees every roster change after its snapshot exactly once.
+    let (mut broadcast_rx, snapshot) = {
+        let mut roster = clients.lock().await;
+        // Checked under the insert's lock, so two clients can't join under one name at once
+        let client = ClientInfo {
+            name: client_name.clone(),
+            addr,
+            stats: Arc::clone(&stats),
+            sas,
+            connected: true,
+        };
+        let announce = match roster.join(client_id, client) {
+            Ok(announce) => announce,
+            Err(reason) => {
+                drop(roster);
+                println!("Refused name '{}' from {}: already in use", client_name, addr);
+                let notice = disconnect_notice(DisconnectReason::InvalidName, format!("Name rejected: {}", reason));
+                close_with_notice(&mut ws_sender, &mut *noise_session.lock().await, notice).await;
+                return;
+            }
+        };
+        let broadcast_rx = broadcast_tx.subscribe();
+        let snapshot = ChatMessage {
+            sender: "Server".to_string(),
+            content: format!("Online: {}", roster.names().join(", ")),
+            control: Some(roster.snapshot()),
+            rich: None,
+            meta: None,
+        };
+        match announce {
+            Some(update) => {
+                let _ = broadcast_tx.send(roster_notice(update));
+                println!("{} joined the chat", client_name);
+            }
+            None => println!("{} reconnected", client_name),
+        }
+        (broadcast_rx, snapshot)
+    };
+    server_stats.joined.fetch_add(1, Ordering::Relaxed);
+
+    let encrypted = noise_session.lock().await.encrypt_message(&snapshot);
+    match encrypted {
+        Ok(encrypted) => {
+            if let Err(err) = ws_sender.send(Message::Binary(encrypted)).await {
+                eprintln!("Failed to send roster: {}", err);
+            }
+        }
+        Err(e) => eprintln!("Failed to send roster to {}: {}", client_name, e),
+    }
+
+    let mut server_cmd_rx = server_cmd_tx.subscribe();
+    let noise_session_recv = Arc::clone(&noise_session);
+    let ws_sender = Arc::new(Mutex::new(ws_sender));
+    let ws_sender_broadcast = Arc::clone(&ws_sender);
+    let ws_sender_server = Arc::clone(&ws_sender);
+    let noise_session_server = Arc::clone(&noise_session);
+    // The name can change via /rename, so every task reads the current one
+    let current_name = Arc::new(Mutex::new(client_name.clone()));
+    let current_name_broadcast = Arc::clone(&current_name);
+    let current_name_server = Arc::clone(&current_name);
+    let stats_broadcast = Arc::clone(&stats);
+    let stats_server = Arc::clone(&stats);
+    let ws_sender_ping = Arc::clone(&ws_sender);
+    // Pings carry the time since this instant, so a pong reveals when its ping left
+    let rtt_epoch = Instant::now();
+    // Server notices waiting for the socket; broadcasts step aside for them
+    let notices = Arc::new(NoticePriority::default());
+    let notices_server = Arc::clone(&notices);
+
+    // Broadcast messages to this client
+    let broadcast_task = tokio::spawn(async move {
+        let mut slow_streak = 0;
+        loop {
+            let chat_msg = match broadcast_rx.recv().await {
+                Ok(chat_msg) => chat_msg,
+                Err(RecvError::Lagged(missed)) => {
+                    let own_name = current_name_broadcast.lock().await.clone();
+                    println!("{} missed {} broadcasts, disconnecting", own_name, missed);
+                    send_disconnect(
+                        &noise_session_recv,
+                        &ws_sender_broadcast,
+                        DisconnectReason::TooSlow,
+                        format!("Disconnected: too slow to keep up ({} messages missed)", missed),
+                    )
+                    .await;
+                    return;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            let mut pending = vec![chat_msg];
+            // A lag noticed after the first message still ends the connection,
+            // once what was already received has been sent
+            let mut lagged = None;
+            if COALESCE_WINDOW_MS != 0 {
+                let deadline = Instant::now() + Duration::from_millis(COALESCE_WINDOW_MS);
+                loop {
+                    match timeout_at(deadline, broadcast_rx.recv()).await {
+                        Ok(Ok(chat_msg)) => pending.push(chat_msg),
+                        Ok(Err(RecvError::Lagged(missed))) => {
+                            lagged = Some(missed);
+                            break;
+                        }
+                        Ok(Err(RecvError::Closed)) | Err(_) => break,
+                    }
+                }
+            }
+
+            let own_name = current_name_broadcast.lock().await.clone();
+            let to_payloads = |messages: &[ChatMessage]| -> Vec<Vec<u8>> {
+                messages
+                    .iter()
+                    .filter(|chat_msg| ECHO_OWN_MESSAGES || chat_msg.sender != own_name)
+                    .filter_map(|chat_msg| match serde_json::to_vec(chat_msg) {
+                        Ok(payload) => Some(payload),
+                        Err(e) => {
+                            eprintln!("Dropped a broadcast from {} that could not be encoded: {}", chat_msg.sender, e);
+                            None
+                        }
+                    })
+                    .collect()
+            };
+            let mut frames = pack_batch(to_payloads(&pending));
+            // How far behind the client is, read before the burst below empties the queue
+            let backlog = broadcast_rx.len();
+            // Take the rest of a burst too, each message in its own frame as
+            // before, so it goes out with a single flush
+            if !FLUSH_EACH_FRAME && lagged.is_none() {
+                loop {
+                    match broadcast_rx.try_recv() {
+                        Ok(chat_msg) => frames.extend(to_payloads(std::slice::from_ref(&chat_msg))),
+                        Err(TryRecvError::Lagged(missed)) => {
+                            lagged = Some(missed);
+                            break;
+                        }
+                        Err(_) => break,
+                    }
+                }
+            }
+
+            let started = Instant::now();
+            let written = write_broadcasts(
+                frames,
+                &noise_session_recv,
+                &ws_sender_broadcast,
+                &notices,
+                &stats_broadcast,
+                FLUSH_EACH_FRAME,
+            );
+            if !written.await {
+                return;
+            }
+            if let Some(missed) = lagged {
+                println!("{} missed {} broadcasts, disconnecting", own_name, missed);
+                send_disconnect(
+                    &noise_session_recv,
+                    &ws_sender_broadcast,
+                    DisconnectReason::TooSlow,
+                    format!("Disconnected: too slow to keep up ({} messages missed)", missed),
+                )
+                .await;
+                return;
+            }
+
+            let elapsed = started.elapsed();
+            let depth = backlog.max(broadcast_rx.len());
+            if elapsed >= Duration::from_millis(SLOW_SEND_MS) || depth >= SLOW_QUEUE_DEPTH {
+                stats_broadcast.slow_sends.fetch_add(1, Ordering::Relaxed);
+                slow_streak += 1;
+                if slow_streak == SLOW_CONSUMER_STREAK {
+                    println!(
+                        "Warning: {} is slow to drain broadcasts (queue depth {}/{}, last send {} ms)",
+                        own_name,
+                        depth,
+                        BROADCAST_CAPACITY,
+                        elapsed.as_millis()
+                    );
+                }
+            } else {
+                slow_streak = 0;
+            }
+        }
+    });
+
+    // Server commands to this client
+    let server_cmd_task = tokio::spawn(async move {
+        while let Ok(cmd) = server_cmd_rx.recv().await {
+            // Check if message is for this client or broadcast
+            let should_send = match &cmd.target {
+                None => true,  // Broadcast to all
+                Some(target_name) => *target_name == *current_name_server.lock().await,  // Targeted message
+            };
+
+            if should_send {
+                let notice = notices_server.announce();
+                let mut session = noise_session_server.lock().await;
+                // A disconnect (e.g. from /ban) must not rely on the client leaving
+                if matches!(cmd.message.control, Some(ControlMessage::Disconnect { .. })) {
+                    let mut sender = ws_sender_server.lock().await;
+                    close_with_notice(&mut sender, &mut session, cmd.message).await;
+                    drop(sender);
+                    drop(session);
+                    drop(notice);
+                    break;
+                }
+                let sent = match session.encrypt_message(&cmd.message) {
+                    Ok(encrypted) => {
+                        stats_server.bytes_out.fetch_add(encrypted.len() as u64, Ordering::Relaxed);
+                        let mut sender = ws_sender_server.lock().await;
+                        sender.send(Message::Binary(encrypted)).await.is_ok()
+                    }
+                    Err(e) => {
+                        eprintln!("Dropped a server message from {}: {}", cmd.message.sender, e);
+                        true
+                    }
+                };
+                drop(session);
+                drop(notice);
+                if !sent {
+                    break;
+                }
+            }
+        }
+    });
+
+    // Measure round-trip time; the client's WebSocket library answers pings itself
+    let ping_task = tokio::spawn(async move {
+        let Some(secs) = PING_INTERVAL_SECS else {
+            return futures_util::future::pending::<()>().await;
+        };
+        let mut interval = tokio::time::interval(Duration::from_secs(secs));
+        loop {
+            interval.tick().await;
+            let sent_us = rtt_epoch.elapsed().as_micros() as u64;
+            let mut sender = ws_sender_ping.lock().await;
+            if sender.send(Message::Ping(sent_us.to_be_bytes().to_vec())).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    // Receive messages from this client
+    let noise_session_send = Arc::clone(&noise_session);
+    let broadcast_tx_clone = broadcast_tx.clone();
+    let server_cmd_tx_clone = server_cmd_tx.clone();
+    let current_name_send = Arc::clone(&current_name);
+    let clients_send = Arc::clone(&clients);
+    let ws_sender_receive = Arc::clone(&ws_sender);
+    let admin = AdminContext {
+        clients: Arc::clone(&clients),
+        stats: Arc::clone(&server_stats),
+        static_key,
+        server_cmd_tx: server_cmd_tx.clone(),
+        draining,
+        name_bans: Arc::clone(&name_bans),
+    };
+    
+    let receive_task = tokio::spawn(async move {
+        let mut quota = CLIENT_BYTE_QUOTA.map(|limit| ByteQuota::new(limit, Duration::from_secs(QUOTA_WINDOW_SECS)));
+        let mut admin_attempts = AdminAttempts::default();
+        while let Some(msg) = ws_receiver.next().await {
+            match msg {
+                Ok(Message::Binary(encrypted_data)) => {
+                    stats.bytes_in.fetch_add(encrypted_data.len() as u64, Ordering::Relaxed);
+                    if let Some(wait) = quota.as_mut().and_then(|q| q.record(encrypted_data.len() as u64)) {
+                        if DISCONNECT_OVER_QUOTA {
+                            println!("{} exceeded its byte quota, disconnecting", *current_name_send.lock().await);
+                            send_disconnect(
+                                &noise_session_send,
+                                &ws_sender_receive,
+                                DisconnectReason::RateLimited,
+                                "Disconnected: rate limited (byte quota exceeded)".to_string(),
+                            )
+                            .await;
+                            break;
+                        }
+                        // Not reading lets TCP backpressure slow the client down
+                        tokio::time::sleep(wait).await;
+                    }
+                    let mut session = noise_session_send.lock().await;
+                    match session.decrypt(&encrypted_data) {
+                        Ok(decrypted) => {
+                            if let Ok(json_str) = String::from_utf8(decrypted) {
+                                if let Ok(mut chat_msg) = serde_json::from_str::<ChatMessage>(&json_str) {
+                                    let sender_name = current_name_send.lock().await.clone();
+                                    chat_msg.sender = sender_name.clone();
+                                    if let Err(reason) = strip_unknown_control(&mut chat_msg, REJECT_UNKNOWN_CONTROL) {
+                                        let _ = server_cmd_tx_clone.send(ServerCommand {
+                                            target: Some(sender_name),
+                                            message: ChatMessage {
+                                                sender: "Server".to_string(),
+                                                content: reason,
+                                                control: None,
+                                                rich: None,
+                                                meta: None,
+                                            },
+                                        });
+                                        continue;
+                                    }
+                                    // Clients may only request a rename, a direct message or an admin command
+                                    let direct_to = match chat_msg.control.take() {
+                                        Some(ControlMessage::Admin { token, command }) => {
+                                            let verdict = admin_attempts.check(admin_auth.as_ref(), &token);
+                                            let authorized = matches!(verdict, AdminVerdict::Authorized);
+                                            let content = if !authorized {
+                                                println!(
+                                                    "Security: rejected admin command from {} ({}), attempt {} of {}",
+                                                    sender_name, addr, admin_attempts.failed, ADMIN_ATTEMPT_LIMIT
+                                                );
+                                                if matches!(verdict, AdminVerdict::Disconnect) {
+                                                    println!("Security: disconnecting {} ({}) after repeated wrong admin tokens", sender_name, addr);
+                                                    let notice = disconnect_notice(
+                                                        DisconnectReason::Unauthorized,
+                                                        "Disconnected: too many wrong admin tokens".to_string(),
+                                                    );
+                                                    let mut sender = ws_sender_receive.lock().await;
+                                                    close_with_notice(&mut sender, &mut session, notice).await;
+                                                    break;
+                                                }
+                                                "Admin command rejected: not authorized".to_string()
+                                            } else {
+                                                println!("Admin command from {}: {}", sender_name, command.trim());
+                                                match run_admin_command(command.trim(), &admin).await {
+                                                    Some(output) => output,
+                                                    None => format!("Unknown admin command '{}'", command.trim()),
+                                                }
+                                            };
+                                            let _ = server_cmd_tx_clone.send(ServerCommand {
+                                                target: Some(sender_name),
+                                                message: ChatMessage {
+                                                    sender: "Server".to_string(),
+                                                    content,
+                                                    control: None,
+                                                    rich: None,
+                                                    meta: None,
+                                                },
+                                            });
+                                            if !authorized {
+                                                // Not reading meanwhile holds back the next guess
+                                                drop(session);
+                                                tokio::time::sleep(Duration::from_secs(ADMIN_RETRY_DELAY_SECS)).await;
+                                            }
+                                            continue;
+                                        }
+                                        Some(ControlMessage::Rename { name }) => {
+                                            let reply = rename_client(
+                                                &clients_send,
+                                                client_id,
+                                                &current_name_send,
+                                                name.trim(),
+                                                &broadcast_tx_clone,
+                                                &name_bans,
+                                            )
+                                            .await;
+                                            if let Err(reason) = reply {
+                                                let _ = server_cmd_tx_clone.send(ServerCommand {
+                                                    target: Some(sender_name),
+                                                    message: ChatMessage {
+                                                        sender: "Server".to_string(),
+                                                        content: reason,
+                                                        control: None,
+                                                        rich: None,
+                                                        meta: None,
+                                                    },
+                                                });
+                                            }
+                                            continue;
+                                        }
+                                        Some(ControlMessage::Direct { to }) => Some(to),
+                                        _ => None,
+                                    };
+                                    if let Some(problem) = chat_msg.rich.as_ref().and_then(|rich| rich.problem(MAX_RICH_PAYLOAD_LEN)) {
+                                        let _ = server_cmd_tx_clone.send(ServerCommand {
+                                            target: Some(sender_name),
+                                            message: ChatMessage {
+                                                sender: "Server".to_string(),
+                                                content: format!("Your message was not delivered: {}", problem),
+                                                control: None,
+                                                rich: None,
+                                                meta: None,
+                                            },
+                                        });
+                                        continue;
+                                    }
+                                    let mut chat_msg = match message_filter.filter(&chat_msg) {
+                                        FilterAction::Allow => chat_msg,
+                                        FilterAction::Modify(modified) => modified,
+                                        FilterAction::Drop(reason) => {
+                                            println!("Dropped message from {}", chat_msg.sender);
+                                            if let Some(reason) = reason {
+                                                let _ = server_cmd_tx_clone.send(ServerCommand {
+                                                    target: Some(sender_name),
+                                                    message: ChatMessage {
+                                                        sender: "Server".to_string(),
+                                                        content: format!("Your message was not delivered: {}", reason),
+                                                        control: None,
+                                                        rich: None,
+                                                        meta: None,
+                                                    },
+                                                });
+                                            }
+                                            continue;
+                                        }
+                                    };
+                                    server_stats.messages_relayed.fetch_add(1, Ordering::Relaxed);
+                                    chat_msg.meta = Some(MessageMeta {
+                                        id: next_message_id.fetch_add(1, Ordering::Relaxed),
+                                        timestamp_ms: SystemTime::now()
+                                            .duration_since(UNIX_EPOCH)
+                                            .map(|d| d.as_millis() as u64)
+                                            .unwrap_or(0),
+                                    });
+                                    match direct_to {
+                                        Some(to) => {
+                                            let holder = clients_send.lock().await.holder(&to);
+                                            if let Err(reason) = holder.direct_target(&to) {
+                                                let _ = server_cmd_tx_clone.send(ServerCommand {
+                                                    target: Some(sender_name),
+                                                    message: ChatMessage {
+                                                        sender: "Server".to_string(),
+                                                        content: reason,
+                                                        control: None,
+                                                        rich: None,
+                                                        meta: None,
+                                                    },
+                                                });
+                                            } else {
+                                                println!("{} -> {}: {}", chat_msg.sender, to, chat_msg.content);
+                                                chat_msg.control = Some(ControlMessage::Direct { to: to.clone() });
+                                                let _ = server_cmd_tx_clone.send(ServerCommand {
+                                                    target: Some(to),
+                                                    message: chat_msg,
+                                                });
+                                            }
+                                        }
+                                        None => {
+                                            println!("{}: {}", chat_msg.sender, chat_msg.content);
+                                            let _ = broadcast_tx_clone.send(chat_msg);
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            eprintln!("Decryption failed for client {} ({}): {}", client_id, addr, e);
+                            if DEBUG_LOGGING {
+                                eprintln!("  {}", session.decrypt_failure_diagnostics(&encrypted_data));
+                            }
+                        }
+                    }
+                }
+                Ok(Message::Pong(data)) => {
+                    if let Ok(sent_us) = <[u8; 8]>::try_from(data.as_slice()) {
+                        let now_us = rtt_epoch.elapsed().as_micros() as u64;
+                        stats.record_rtt(now_us.saturating_sub(u64::from_be_bytes(sent_us)));
+                    }
+                }
+                Ok(Message::Close(_)) => {
+                    println!("{} disconnected", *current_name_send.lock().await);
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("WebSocket error from {}: {}", *current_name_send.lock().await, e);
+                    break;
+                }
+                _ => {}
+            }
+        }
+    });
+
+    supervise_tasks(
+        vec![
+            ("broadcast", broadcast_task),
+            ("server command", server_cmd_task),
+            ("receive", receive_task),
+            ("ping", ping_task),
+        ],
+        client_id,
+        addr,
+    )
+    .await;
+
+    if RECONNECT_GRACE_SECS == 0 {
+        remove_client(&clients, client_id, &broadcast_tx).await;
+        return;
+    }
+
+    if let Some(client) = clients.lock().await.clients.get_mut(&client_id) {
+        client.connected = false;
+    }
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(RECONNECT_GRACE_SECS)).await;
+        remove_client(&clients, client_id, &broadcast_tx).await;
+    });
+}
+
+/// Waits until the first of a connection's tasks ends (or panics), then aborts
+/// the others so none of them outlives the cleanup that follows. Returns
+/// whether the task that ended had panicked.
+async fn supervise_tasks(tasks: Vec<(&'static str, JoinHandle<()>)>, client_id: u32, addr: SocketAddr) -> bool {
+    let (names, mut handles): (Vec<_>, Vec<_>) = tasks.into_iter().unzip();
+    let (result, index, _) = futures_util::future::select_all(handles.iter_mut()).await;
+    for handle in &handles {
+        handle.abort();
+    }
+    let panicked = result.is_err_and(|err| err.is_panic());
+    if panicked {
+        eprintln!("Client {} ({}): {} task panicked, disconnecting", client_id, addr, names[index]);
+    }
+    panicked
+}
+
+/// Drops a client from the roster and announces it, unless a reconnect has
+/// already taken over its entry.
+async fn remove_client(
+    clients: &Mutex<Roster>,
+    client_id: u32,
+    broadcast_tx: &broadcast::Sender<ChatMessage>,
+) {
+    if let Some(update) = clients.lock().await.remove(client_id) {
+        let _ = broadcast_tx.send(roster_notice(update));
+    }
+}
+
+/// Tells the client why it is being dropped, then closes the WebSocket. The
+/// reason travels encrypted, ahead of the close frame.
+async fn send_disconnect(
+    noise_session: &Mutex<NoiseSession>,
+    ws_sender: &Mutex<WsSender>,
+    reason: DisconnectReason,
+    detail: String,
+) {
+    let notice = disconnect_notice(reason, detail);
+    let mut session = noise_session.lock().await;
+    let mut sender = ws_sender.lock().await;
+    close_with_notice(&mut sender, &mut session, notice).await;
+}
+
+/// Sends a last notice, then the close frame.
+async fn close_with_notice(ws_sender: &mut WsSender, noise_session: &mut NoiseSession, notice: ChatMessage) {
+    match noise_session.encrypt_message(&notice) {
+        Ok(encrypted) => {
+            let _ = ws_sender.send(Message::Binary(encrypted)).await;
+        }
+        Err(e) => eprintln!("Failed to send notice '{}': {}", notice.content, e),
+    }
+    let _ = ws_sender.send(Message::Close(None)).await;
+}
+
+fn disconnect_notice(reason: DisconnectReason, detail: String) -> ChatMessage {
+    ChatMessage {
+        sender: "Server".to_string(),
+        content: detail,
+        control: Some(ControlMessage::Disconnect {
+            reason,
+            reconnect: reason.reconnectable(),
+        }),
+        rich: None,
+        meta: None,
+    }
+}
+
+/// Trims a requested name and checks it against MAX_NAME_LEN and the allowed
+/// characters, so a name can't carry newlines or escape sequences into the
+/// server console and logs.
+fn validate_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("Name cannot be empty".to_string());
+    }
+    if name.chars().count() > MAX_NAME_LEN {
+        return Err(format!("Name is longer than {} characters", MAX_NAME_LEN));
+    }
+    if let Some(c) = name.chars().find(|&c| !c.is_alphanumeric() && !NAME_EXTRA_CHARS.contains(c)) {
+        return Err(format!(
+            "Name contains {:?}; use letters, digits or one of \"{}\"",
+            c, NAME_EXTRA_CHARS
+        ));
+    }
+    // Server notices are sent as "Server"; a client by that name could pass
+    // for the server and would filter out its own roster updates
+    if name.eq_ignore_ascii_case("Server") {
+        return Err("Name 'Server' is reserved".to_string());
+    }
+    Ok(name.to_string())
+}
+
+/// Upgrades the connection, performs the Noise handshake and asks for the
+/// client's name. Failures are logged here and yield why the client didn't join.
+async fn accept_client(
+    stream: TcpStream,
+    static_key: Option<StaticKeypair>,
+    name_bans: &std::sync::Mutex<NameBans>,
+    observer_auth: &dyn AdminAuth,
+) -> Result<(WsSender, WsReceiver, NoiseSession, Joined), NotJoined> {
+    let ws_stream = match accept_hdr_async_with_config(stream, negotiate_subprotocol, Some(websocket_config())).await {
+        Ok(ws) => ws,
+        Err(err) => {
+            eprintln!("Failed to accept WebSocket: {}", err);
+            return Err(NotJoined::HandshakeFailed);
+        }
+    };
+
+    println!("WebSocket connection established");
+    println!("Starting Noise handshake...");
+
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+
+    let outcome = match perform_noise_handshake_responder(&mut ws_sender, &mut ws_receiver, static_key.as_ref()).await {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            eprintln!("Noise handshake failed: {}", e);
+            return Err(NotJoined::HandshakeFailed);
+        }
+    };
+
+    println!(
+        "Secure channel established ({}, {}, PSK {}, client key {})",
+        outcome.pattern,
+        outcome.version,
+        outcome.psk_source,
+        outcome.remote_static_fp.as_deref().unwrap_or("none")
+    );
+    let mut noise_session = outcome.session;
+
+    // Request client name
+    let name_request = ChatMessage {
+        sender: "Server".to_string(),
+        content: "Please enter your name:".to_string(),
+        control: None,
+        rich: None,
+        meta: None,
+    };
+    
+    let encrypted = match noise_session.encrypt_message(&name_request) {
+        Ok(encrypted) => encrypted,
+        Err(e) => {
+            eprintln!("Failed to request name: {}", e);
+            return Err(NotJoined::Refused);
+        }
+    };
+    if let Err(err) = ws_sender.send(Message::Binary(encrypted)).await {
+        eprintln!("Failed to request name: {}", err);
+        return Err(NotJoined::Refused);
+    }
+
+    // Wait for client name
+    let name_reply = match ws_receiver.next().await {
+        Some(Ok(Message::Binary(encrypted_data))) => {
+            let reply = noise_session
+                .decrypt(&encrypted_data)
+                .ok()
+                .and_then(|decrypted| String::from_utf8(decrypted).ok())
+                .and_then(|json_str| serde_json::from_str::<ChatMessage>(&json_str).ok());
+            match reply {
+                Some(chat_msg) => chat_msg,
+                None => return Err(NotJoined::Refused),
+            }
+        }
+        _ => return Err(NotJoined::Refused),
+    };
+
+    if let Some(ControlMessage::Observe { token }) = &name_reply.control {
+        if observer_auth.authenticate(token) {
+            return Ok((ws_sender, ws_receiver, noise_session, Joined::Observer));
+        }
+        println!("Security: refused observer with a wrong token");
+        let notice = disconnect_notice(DisconnectReason::Unauthorized, "Observer access refused".to_string());
+        close_with_notice(&mut ws_sender, &mut noise_session, notice).await;
+        return Err(NotJoined::HandshakeFailed);
+    }
+
+    let client_name = match validate_name(&name_reply.content) {
+        Ok(name) => name,
+        Err(reason) => {
+            println!("Rejected name from new client: {}", reason);
+            let notice = disconnect_notice(DisconnectReason::InvalidName, format!("Name rejected: {}", reason));
+            close_with_notice(&mut ws_sender, &mut noise_session, notice).await;
+            return Err(NotJoined::Refused);
+        }
+    };
+
+    if name_bans.lock().unwrap().is_banned(&client_name) {
+        println!("Security: refused banned name '{}'", client_name);
+        let notice = disconnect_notice(DisconnectReason::Banned, "This name is banned from the server".to_string());
+        close_with_notice(&mut ws_sender, &mut noise_session, notice).await;
+        return Err(NotJoined::Refused);
+    }
+
+    Ok((ws_sender, ws_receiver, noise_session, Joined::Participant(client_name)))
+}
+
+/// Whether an observer is shown a server command: everything sent to all
+/// clients, and messages between clients. Replies meant for one client stay
+/// private.
+fn observer_sees(cmd: &ServerCommand) -> bool {
+    cmd.target.is_none() || matches!(cmd.message.control, Some(ControlMessage::Direct { .. }))
+}
+
+/// Serves an observer: every broadcast, server-wide notice and private message
+/// between clients is forwarded to it, and anything it sends is refused.
+/// Observers are not in the roster, so nobody can see or address them.
+async fn observe(
+    mut ws_sender: WsSender,
+    mut ws_receiver: WsReceiver,
+    mut noise_session: NoiseSession,
+    clients: &Mutex<Roster>,
+    broadcast_tx: &broadcast::Sender<ChatMessage>,
+    server_cmd_tx: &broadcast::Sender<ServerCommand>,
+) {
+    let (mut broadcast_rx, mut server_cmd_rx, snapshot) = {
+        let roster = clients.lock().await;
+        let snapshot = ChatMessage {
+            sender: "Server".to_string(),
+            content: format!("Observing. Online: {}", roster.names().join(", ")),
+            control: Some(ControlMessage::RosterSnapshot {
+                version: roster.version,
+                names: roster.names(),
+            }),
+            rich: None,
+            meta: None,
+        };
+        (broadcast_tx.subscribe(), server_cmd_tx.subscribe(), snapshot)
+    };
+
+    let mut outgoing = Some(snapshot);
+    loop {
+        if let Some(chat_msg) = outgoing.take() {
+            match noise_session.encrypt_message(&chat_msg) {
+                Ok(encrypted) => {
+                    if ws_sender.send(Message::Binary(encrypted)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => eprintln!("Failed to forward a message from {} to an observer: {}", chat_msg.sender, e),
+            }
+        }
+        let missed = tokio::select! {
+            received = broadcast_rx.recv() => match received {
+                Ok(chat_msg) => {
+                    outgoing = Some(chat_msg);
+                    continue;
+                }
+                Err(RecvError::Lagged(missed)) => missed,
+                Err(RecvError::Closed) => return,
+            },
+            received = server_cmd_rx.recv() => match received {
+                Ok(cmd) if !observer_sees(&cmd) => continue,
+                // A server-wide disconnect, e.g. at the end of a drain, applies to observers too
+                Ok(cmd) if matches!(cmd.message.control, Some(ControlMessage::Disconnect { .. })) => {
+                    close_with_notice(&mut ws_sender, &mut noise_session, cmd.message).await;
+                    return;
+                }
+                Ok(cmd) => {
+                    outgoing = Some(cmd.message);
+                    continue;
+                }
+                Err(RecvError::Lagged(missed)) => missed,
+                Err(RecvError::Closed) => return,
+            },
+            received = ws_receiver.next() => match received {
+                Some(Ok(Message::Binary(_))) => {
+                    outgoing = Some(ChatMessage {
+                        sender: "Server".to_string(),
+                        content: "Observers cannot send messages".to_string(),
+                        control: None,
+                        rich: None,
+                        meta: None,
+                    });
+                    continue;
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+        };
+        let notice = disconnect_notice(
+            DisconnectReason::TooSlow,
+            format!("Disconnected: too slow to keep up ({} messages missed)", missed),
+        );
+        close_with_notice(&mut ws_sender, &mut noise_session, notice).await;
+        return;
+    }
+}
+
+/// Renames a connected client and announces it, returning the reason on refusal.
+async fn rename_client(
+    clients: &Mutex<Roster>,
+    client_id: u32,
+    current_name: &Mutex<String>,
+    new_name: &str,
+    broadcast_tx: &broadcast::Sender<ChatMessage>,
+    name_bans: &std::sync::Mutex<NameBans>,
+) -> Result<(), String> {
+    let new_name = &validate_name(new_name)?;
+    if name_bans.lock().unwrap().is_banned(new_name) {
+        return Err(format!("Name '{}' is not allowed", new_name));
+    }
+
+    let mut roster = clients.lock().await;
+    let updates = roster.rename(client_id, new_name)?;
+    let mut current_name = current_name.lock().await;
+    println!("{} is now known as {}", current_name, new_name);
+    *current_name = new_name.to_string();
+    for update in updates {
+        let _ = broadcast_tx.send(roster_notice(update));
+    }
+    Ok(())
+}
+
+async fn perform_noise_handshake_responder(
+    ws_sender: &mut WsSender,
+    ws_receiver: &mut WsReceiver,
+    static_key: Option<&StaticKeypair>,
+) -> Result<HandshakeOutcome, Box<dyn std::error::Error>> {
+    let mut handshake = create_responder(static_key)?;
+    let mut buf = vec![0u8; handshake_buffer_len()?];
+
+    if let Some(msg) = ws_receiver.next().await {
+        match msg? {
+            Message::Binary(data) => {
+                // The protocol name was already compared at the upgrade (see
+                // check_noise_protocol); the first payload carries nothing we need
+                handshake.read_message(&data, &mut buf).map_err(|e| match e {
+                    snow::Error::Decrypt => NoiseError::HandshakeError(
+                        "the client's first message failed to decrypt; it most likely uses a different Noise protocol".to_string(),
+                    ),
+                    e => NoiseError::HandshakeError(e.to_string()),
+                })?;
+                let len = handshake.write_message(SUBPROTOCOL.as_bytes(), &mut buf)?;
+                ws_sender.send(Message::Binary(buf[..len].to_vec())).await?;
+
+                if let Some(msg) = ws_receiver.next().await {
+                    match msg? {
+                        Message::Binary(data) => {
+                            let len = handshake.read_message(&data, &mut buf)?;
+                            let version = check_bound_protocol(&buf[..len])?;
+                            let handshake_hash = handshake.get_handshake_hash().to_vec();
+                            let transport = handshake.into_transport_mode()?;
+                            let session = NoiseSession::new(transport, handshake_hash);
+                            let pattern = noise_pattern();
+                            Ok(HandshakeOutcome {
+                                pattern,
+                                psk_source: "static (compiled in, not QKD)",
+                                version,
+                                remote_static_fp: session.remote_static().map(fingerprint),
+                                session,
+                            })
+                        }
+                        Message::Close(_) => Err(
+                            "client closed after our second handshake message; it most likely could not decrypt it, i.e. the PSKs differ"
+                                .into(),
+                        ),
+                        _ => Err("Expected binary message".into()),
+                    }
+                } else {
+                    Err("Connection closed".into())
+                }
+            }
+            _ => Err("Expected binary message".into()),
+        }
+    } else {
+        Err("Connection closed".into())
+    }
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    // Mirrors the client's unpack_batch
+    fn unpack(frame: &[u8]) -> Vec<Vec<u8>> {
+        if frame.first() != Some(&BATCH_MARKER) {
+            return vec![frame.to_vec()];
+        }
+        let mut messages = Vec::new();
+        let mut rest = &frame[1..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            messages.push(rest[4..4 + len].to_vec());
+            rest = &rest[4 + len..];
+        }
+        messages
+    }
+
+    #[test]
+    fn pack_batch_leaves_a_single_message_alone() {
+        let message = b"{\"sender\":\"a\",\"content\":\"hi\"}".to_vec();
+        assert_eq!(pack_batch(vec![message.clone()]), vec![message]);
+    }
+
+    #[test]
+    fn pack_batch_round_trip() {
+        let messages: Vec<Vec<u8>> = (0..10).map(|i| format!("{{\"n\":{}}}", i).into_bytes()).collect();
+        let frames = pack_batch(messages.clone());
+        assert_eq!(frames.len(), 1);
+        assert_eq!(unpack(&frames[0]), messages);
+    }
+
+    #[test]
+    fn pack_batch_splits_at_the_frame_limit() {
+        let messages = vec![vec![b'a'; MAX_PLAINTEXT_LEN / 2]; 3];
+        let frames = pack_batch(messages.clone());
+        assert!(frames.len() > 1);
+        assert!(frames.iter().all(|frame| frame.len() <= MAX_PLAINTEXT_LEN));
+        let unpacked: Vec<Vec<u8>> = frames.iter().flat_map(|frame| unpack(frame)).collect();
+        assert_eq!(unpacked, messages);
+    }
+
+    #[test]
+    fn pack_batch_sends_an_oversized_message_alone_in_order() {
+        let big = vec![b'b'; MAX_PLAINTEXT_LEN - 2];
+        let messages = vec![b"1".to_vec(), big.clone(), b"2".to_vec()];
+        let frames = pack_batch(messages.clone());
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[1], big);
+        let unpacked: Vec<Vec<u8>> = frames.iter().flat_map(|frame| unpack(frame)).collect();
+        assert_eq!(unpacked, messages);
+    }
+
+    #[test]
+    fn validate_name_accepts_and_trims() {
+        assert_eq!(validate_name("  Alice_1  "), Ok("Alice_1".to_string()));
+        assert_eq!(validate_name("j.doe-2"), Ok("j.doe-2".to_string()));
+        assert_eq!(validate_name("Zoë"), Ok("Zoë".to_string()));
+    }
+
+    #[test]
+    fn validate_name_rejects_bad_names() {
+        assert!(validate_name("").is_err());
+        assert!(validate_name("   ").is_err());
+        assert!(validate_name("two words").is_err());
+        assert!(validate_name("bell\x07").is_err());
+        assert!(validate_name("\x1b[31mred").is_err());
+        assert!(validate_name(&"a".repeat(MAX_NAME_LEN + 1)).is_err());
+        assert!(validate_name(&"a".repeat(MAX_NAME_LEN)).is_ok());
+    }
+
+    #[test]
+    fn validate_name_reserves_server() {
+        assert!(validate_name("Server").is_err());
+        assert!(validate_name("server").is_err());
+        assert!(validate_name(" SERVER ").is_err());
+        assert!(validate_name("Server2").is_ok());
+    }
+
+    #[test]
+    fn byte_quota_allows_up_to_the_limit() {
+        let mut quota = ByteQuota::new(100, Duration::from_secs(60));
+        assert_eq!(quota.record(60), None);
+        assert_eq!(quota.record(40), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn byte_quota_waits_for_the_oldest_frames_to_age_out() {
+        let window = Duration::from_secs(60);
+        let mut quota = ByteQuota::new(100, window);
+        assert_eq!(quota.record(20), None);
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(quota.record(70), None);
+        tokio::time::advance(Duration::from_secs(5)).await;
+        // 30 bytes over: both earlier frames must age out, the second 55 s from now
+        assert_eq!(quota.record(40), Some(Duration::from_secs(55)));
+        tokio::time::advance(Duration::from_secs(45)).await;
+        // The first has gone, leaving the quota 10 bytes over until the second goes
+        assert_eq!(quota.record(0), Some(Duration::from_secs(10)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn byte_quota_forgets_frames_outside_the_window() {
+        let mut quota = ByteQuota::new(100, Duration::from_secs(60));
+        assert!(quota.record(150).is_some());
+        tokio::time::advance(Duration::from_secs(59)).await;
+        assert!(quota.record(0).is_some());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(quota.record(50), None);
+    }
+
+    #[test]
+    fn handshake_failures_ban_at_the_limit() {
+        let mut failures = HandshakeFailures::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(!failures.record_against(ip, 3));
+        assert!(!failures.record_against(ip, 3));
+        assert_eq!(failures.ban_remaining(ip), None);
+        assert!(failures.record_against(ip, 3));
+        let remaining = failures.ban_remaining(ip).expect("banned");
+        assert!(remaining <= Duration::from_secs(HANDSHAKE_BAN_SECS));
+    }
+
+    #[test]
+    fn handshake_failures_count_each_ip_separately() {
+        let mut failures = HandshakeFailures::default();
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "2001:db8::1".parse().unwrap();
+        assert!(!failures.record_against(a, 2));
+        assert!(!failures.record_against(b, 2));
+        assert_eq!(failures.ban_remaining(a), None);
+        assert_eq!(failures.ban_remaining(b), None);
+        assert!(failures.record_against(b, 2));
+        assert_eq!(failures.ban_remaining(a), None);
+        assert!(failures.ban_remaining(b).is_some());
+    }
+
+    // Runs an in-memory handshake and returns the (initiator, responder) sessions
+    fn handshake_states() -> (HandshakeState, HandshakeState) {
+        let client_key = StaticKeypair::generate().unwrap();
+        let initiator = Builder::new(noise_params().unwrap())
+            .local_private_key(&client_key.private)
+            .psk(2, PSK)
+            .build_initiator()
+            .unwrap();
+        (initiator, create_responder(None).unwrap())
+    }
+
+    fn handshake_pair() -> (NoiseSession, NoiseSession) {
+        let (mut initiator, mut responder) = handshake_states();
+        let mut message = vec![0u8; handshake_buffer_len().unwrap()];
+        let mut payload = vec![0u8; handshake_buffer_len().unwrap()];
+
+        let len = initiator.write_message(&[], &mut message).unwrap();
+        responder.read_message(&message[..len], &mut payload).unwrap();
+        let len = responder.write_message(&[], &mut message).unwrap();
+        initiator.read_message(&message[..len], &mut payload).unwrap();
+        let len = initiator.write_message(&[], &mut message).unwrap();
+        responder.read_message(&message[..len], &mut payload).unwrap();
+
+        let finish = |handshake: HandshakeState| {
+            let handshake_hash = handshake.get_handshake_hash().to_vec();
+            NoiseSession::new(handshake.into_transport_mode().unwrap(), handshake_hash)
+        };
+        (finish(initiator), finish(responder))
+    }
+
+    #[test]
+    fn sas_matches_on_both_ends() {
+        let (client, server) = handshake_pair();
+        assert_eq!(client.sas(), server.sas());
+    }
+
+    #[test]
+    fn sas_is_two_groups_of_three_digits() {
+        let (_, server) = handshake_pair();
+        let sas = server.sas();
+        let (first, second) = sas.split_once(' ').expect("space-separated");
+        assert_eq!(first.len(), 3);
+        assert_eq!(second.len(), 3);
+        assert!(first.chars().chain(second.chars()).all(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn describe_protocol_mismatch_names_the_differing_components() {
+        assert_eq!(
+            describe_protocol_mismatch("Noise_XXpsk2_25519_ChaChaPoly_SHA256", "Noise_XXpsk2_25519_AESGCM_SHA256"),
+            "cipher ChaChaPoly vs AESGCM"
+        );
+        assert_eq!(
+            describe_protocol_mismatch("Noise_XX_25519_AESGCM_BLAKE2s", "Noise_XXpsk2_25519_AESGCM_SHA256"),
+            "handshake pattern (and PSK position) XX vs XXpsk2, hash BLAKE2s vs SHA256"
+        );
+    }
+
+    #[test]
+    fn describe_protocol_mismatch_falls_back_to_the_full_names() {
+        // Same components but a different length, so no single component differs
+        assert_eq!(
+            describe_protocol_mismatch("Noise_XXpsk2_25519_AESGCM", "Noise_XXpsk2_25519_AESGCM_SHA256"),
+            "Noise_XXpsk2_25519_AESGCM vs Noise_XXpsk2_25519_AESGCM_SHA256"
+        );
+    }
+
+    fn client_info(name: &str) -> ClientInfo {
+        ClientInfo {
+            name: name.to_string(),
+            addr: "127.0.0.1:1".parse().unwrap(),
+            stats: Arc::new(ConnectionStats::default()),
+            sas: String::new(),
+            connected: true,
+        }
+    }
+
+    // Mirrors the client's Roster::apply, but fails on any update it would ignore
+    struct ClientView {
+        version: u64,
+        names: Vec<String>,
+    }
+
+    impl ClientView {
+        fn from_snapshot(roster: &Roster) -> Self {
+            let ControlMessage::RosterSnapshot { version, names } = roster.snapshot() else {
+                panic!("not a snapshot");
+            };
+            Self { version, names }
+        }
+
+        fn apply(&mut self, update: ControlMessage) {
+            let version = match &update {
+                ControlMessage::RosterJoin { version, .. }
+                | ControlMessage::RosterLeave { version, .. }
+                | ControlMessage::RosterRename { version, .. } => *version,
+                other => panic!("not a roster update: {:?}", other),
+            };
+            assert!(version > self.version, "client would drop version {} after {}", version, self.version);
+            self.version = version;
+            match update {
+                ControlMessage::RosterJoin { name, .. } => self.names.push(name),
+                ControlMessage::RosterLeave { name, .. } => self.names.retain(|n| *n != name),
+                ControlMessage::RosterRename { old, new, .. } => {
+                    for name in self.names.iter_mut().filter(|n| **n == old) {
+                        *name = new.clone();
+                    }
+                }
+                _ => unreachable!(),
+            }
+            self.names.sort();
+        }
+    }
+
+    #[test]
+    fn roster_updates_keep_a_client_in_sync() {
+        let mut roster = Roster::default();
+        roster.join(1, client_info("Alice")).unwrap();
+        let mut view = ClientView::from_snapshot(&roster);
+
+        view.apply(roster.join(2, client_info("Bob")).unwrap().expect("announced"));
+        for update in roster.rename(2, "Carol").unwrap() {
+            view.apply(update);
+        }
+        view.apply(roster.join(3, client_info("Bob")).unwrap().expect("announced"));
+        view.apply(roster.remove(1).expect("announced"));
+        assert_eq!(view.names, roster.names());
+        assert_eq!(view.names, ["Bob", "Carol"]);
+        assert_eq!(view.version, roster.version);
+    }
+
+    #[test]
+    fn roster_refuses_a_connected_name_on_join_and_rename() {
+        let mut roster = Roster::default();
+        roster.join(1, client_info("Alice")).unwrap();
+        roster.join(2, client_info("Bob")).unwrap();
+        assert!(roster.join(3, client_info("Alice")).is_err());
+        assert!(roster.rename(2, "Alice").is_err());
+        // Names are compared exactly
+        assert!(roster.join(3, client_info("alice")).is_ok());
+        assert_eq!(roster.names(), ["Alice", "Bob", "alice"]);
+    }
+
+    #[test]
+    fn roster_hands_an_away_name_to_the_next_join_or_rename() {
+        let mut roster = Roster::default();
+        roster.join(1, client_info("Alice")).unwrap();
+        roster.join(2, client_info("Bob")).unwrap();
+        roster.clients.get_mut(&1).unwrap().connected = false;
+        let mut view = ClientView::from_snapshot(&roster);
+
+        // A join takes the entry over without anyone seeing a change
+        assert!(roster.join(3, client_info("Alice")).unwrap().is_none());
+        assert!(!roster.clients.contains_key(&1));
+
+        // A rename retires the away entry first, announced as a leave
+        roster.clients.get_mut(&3).unwrap().connected = false;
+        let updates = roster.rename(2, "Alice").unwrap();
+        assert!(matches!(&updates[0], ControlMessage::RosterLeave { name, .. } if name == "Alice"));
+        for update in updates {
+            view.apply(update);
+        }
+        assert_eq!(view.names, roster.names());
+        assert_eq!(view.names, ["Alice"]);
+        // The retired entry's grace timer then finds nothing to announce
+        assert!(roster.remove(3).is_none());
+    }
+
+    #[test]
+    fn rejoin_within_grace_keeps_the_roster_unchanged() {
+        let mut roster = Roster::default();
+        roster.join(1, client_info("Alice")).unwrap();
+        roster.join(2, client_info("Bob")).unwrap();
+        let version = roster.version;
+
+        // Alice drops and waits out the grace period
+        roster.clients.get_mut(&1).unwrap().connected = false;
+        assert_eq!(roster.holder("Alice").direct_target("Alice"), Err("Client 'Alice' is away; the message was not delivered".to_string()));
+
+        // Rejoining takes the entry over: same names, same version, nothing to announce
+        assert!(roster.join(3, client_info("Alice")).unwrap().is_none());
+        assert_eq!(roster.version, version);
+        assert_eq!(roster.names(), ["Alice", "Bob"]);
+        assert_eq!(roster.holder("Alice").direct_target("Alice"), Ok(()));
+        // The old connection's grace timer finds its entry gone
+        assert!(roster.remove(1).is_none());
+        assert_eq!(roster.version, version);
+    }
+
+    #[test]
+    fn grace_expiry_announces_the_leave() {
+        let mut roster = Roster::default();
+        roster.join(1, client_info("Alice")).unwrap();
+        roster.clients.get_mut(&1).unwrap().connected = false;
+        assert!(matches!(roster.remove(1), Some(ControlMessage::RosterLeave { name, .. }) if name == "Alice"));
+        assert_eq!(roster.holder("Alice").direct_target("Alice"), Err("Client 'Alice' not found".to_string()));
+    }
+
+    fn server_command(target: Option<&str>, control: Option<ControlMessage>) -> ServerCommand {
+        ServerCommand {
+            target: target.map(str::to_string),
+            message: ChatMessage {
+                sender: "Server".to_string(),
+                content: String::new(),
+                control,
+                rich: None,
+                meta: None,
+            },
+        }
+    }
+
+    #[test]
+    fn observers_see_server_wide_commands_and_direct_messages() {
+        assert!(observer_sees(&server_command(None, None)));
+        assert!(observer_sees(&server_command(None, Some(ControlMessage::System))));
+        assert!(observer_sees(&server_command(None, Some(ControlMessage::Redirect { url: "ws://other:8080".to_string() }))));
+        assert!(observer_sees(&server_command(
+            None,
+            Some(ControlMessage::Disconnect { reason: DisconnectReason::Shutdown, reconnect: true })
+        )));
+        assert!(observer_sees(&server_command(Some("Bob"), Some(ControlMessage::Direct { to: "Bob".to_string() }))));
+    }
+
+    #[test]
+    fn observers_dont_see_replies_to_one_client() {
+        assert!(!observer_sees(&server_command(Some("Bob"), None)));
+        assert!(!observer_sees(&server_command(
+            Some("Bob"),
+            Some(ControlMessage::Disconnect { reason: DisconnectReason::Banned, reconnect: false })
+        )));
+    }
+
+    #[test]
+    fn deny_all_refuses_every_credential() {
+        assert!(!DenyAll.authenticate(""));
+        assert!(!DenyAll.authenticate("anything"));
+    }
+
+    #[test]
+    fn observer_and_admin_tokens_are_checked_separately() {
+        let admin = TokenAuth::new("admin secret");
+        let observer = TokenAuth::new("observer secret");
+        assert!(observer.authenticate("observer secret"));
+        assert!(!observer.authenticate("admin secret"));
+        assert!(!admin.authenticate("observer secret"));
+    }
+
+    fn text(content: &str) -> ChatMessage {
+        ChatMessage {
+            sender: "Alice".to_string(),
+            content: content.to_string(),
+            control: None,
+            rich: None,
+            meta: None,
+        }
+    }
+
+    #[test]
+    fn word_list_filter_allows_clean_messages() {
+        let filter = WordListFilter::new(&["spam"], false);
+        assert!(matches!(filter.filter(&text("spammer and spam-free")), FilterAction::Allow));
+    }
+
+    #[test]
+    fn word_list_filter_masks_blocked_words() {
+        let filter = WordListFilter::new(&["Spam"], false);
+        match filter.filter(&text("no SPAM, please")) {
+            FilterAction::Modify(msg) => {
+                assert_eq!(msg.content, "no ***** please");
+                assert_eq!(msg.sender, "Alice");
+            }
+            _ => panic!("expected the message to be masked"),
+        }
+    }
+
+    #[test]
+    fn word_list_filter_drops_blocked_words_when_configured() {
+        let filter = WordListFilter::new(&["spam"], true);
+        match filter.filter(&text("buy spam now")) {
+            FilterAction::Drop(Some(reason)) => assert_eq!(reason, "message contains a blocked word"),
+            _ => panic!("expected the message to be dropped with a reason"),
+        }
+    }
+
+    #[test]
+    fn token_auth_accepts_only_the_exact_token() {
+        let auth = TokenAuth::new("s3cret");
+        assert!(auth.authenticate("s3cret"));
+        assert!(!auth.authenticate("s3cret "));
+        assert!(!auth.authenticate("S3CRET"));
+        assert!(!auth.authenticate(""));
+    }
+
+    #[test]
+    fn token_auth_with_an_empty_token_only_accepts_empty() {
+        let auth = TokenAuth::new("");
+        assert!(auth.authenticate(""));
+        assert!(!auth.authenticate("anything"));
+    }
+
+    #[test]
+    fn admin_attempts_disconnect_after_the_limit() {
+        let auth = TokenAuth::new("s3cret");
+        let mut attempts = AdminAttempts::default();
+        assert!(matches!(attempts.check_against(&auth, "guess", 3), AdminVerdict::Rejected));
+        assert!(matches!(attempts.check_against(&auth, "s3cret", 3), AdminVerdict::Authorized));
+        // A right token in between doesn't forgive earlier guesses
+        assert!(matches!(attempts.check_against(&auth, "guess", 3), AdminVerdict::Rejected));
+        assert!(matches!(attempts.check_against(&auth, "guess", 3), AdminVerdict::Disconnect));
+        assert_eq!(attempts.failed, 3);
+    }
+
+    fn admin_context() -> AdminContext {
+        AdminContext {
+            clients: Arc::new(Mutex::new(Roster::default())),
+            stats: Arc::new(ServerStats::new()),
+            static_key: Arc::new(Mutex::new(None)),
+            server_cmd_tx: broadcast::channel(8).0,
+            draining: Arc::new(AtomicBool::new(false)),
+            name_bans: Arc::new(std::sync::Mutex::new(NameBans::default())),
+        }
+    }
+
+    #[tokio::test]
+    async fn stats_command_reports_the_counters() {
+        let admin = admin_context();
+        admin.clients.lock().await.join(1, client_info("Alice")).unwrap();
+        admin.stats.joined.store(4, Ordering::Relaxed);
+        admin.stats.failed_handshakes.store(2, Ordering::Relaxed);
+        admin.stats.handshake_timeouts.store(1, Ordering::Relaxed);
+        admin.stats.messages_relayed.store(17, Ordering::Relaxed);
+        let out = run_admin_command("/stats", &admin).await.unwrap();
+        assert!(out.contains("Uptime:            0h 00m"));
+        assert!(out.contains("Clients online:    1\n"));
+        assert!(out.contains("Clients joined:    4\n"));
+        assert!(out.contains("Failed handshakes: 3 (1 timed out)\n"));
+        assert!(out.contains("Messages relayed:  17\n"));
+        assert!(out.contains("IP bans issued:    0"));
+        assert!(!out.contains("Draining"));
+    }
+
+    #[tokio::test]
+    async fn list_command_counts_away_clients_separately() {
+        let admin = admin_context();
+        {
+            let mut roster = admin.clients.lock().await;
+            let alice = client_info("Alice");
+            alice.stats.bytes_in.store(120, Ordering::Relaxed);
+            alice.stats.bytes_out.store(340, Ordering::Relaxed);
+            alice.stats.record_rtt(2500);
+            roster.join(1, alice).unwrap();
+            roster.join(2, client_info("Bob")).unwrap();
+            roster.clients.get_mut(&2).unwrap().connected = false;
+        }
+        let out = run_admin_command("/list", &admin).await.unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "1 client(s) connected, 1 away");
+        assert_eq!(lines[1], "  Alice (127.0.0.1:1) in: 120 bytes, out: 340 bytes, slow sends: 0, rtt: 2.5 ms");
+        assert_eq!(lines[2], "  Bob (127.0.0.1:1) away, in: 0 bytes, out: 0 bytes, slow sends: 0, rtt: -");
+    }
+
+    #[test]
+    fn noise_params_accepts_the_supported_protocols() {
+        for pattern in [
+            "Noise_XXpsk2_25519_ChaChaPoly_BLAKE2s",
+            "Noise_XXpsk2_25519_AESGCM_SHA256",
+            "Noise_XXpsk2_25519_ChaChaPoly_SHA512",
+            "Noise_XXpsk2_25519_AESGCM_BLAKE2b",
+        ] {
+            assert!(parse_noise_params(pattern).is_ok(), "{} should be accepted", pattern);
+        }
+    }
+
+    #[test]
+    fn noise_params_names_the_unsupported_component() {
+        let cases = [
+            ("Noise_XXpsk2_25519_ChaChaPoly", "is not of the form"),
+            ("Noise_NN_25519_ChaChaPoly_BLAKE2s", "Unsupported handshake pattern 'NN'"),
+            ("Noise_XXpsk2_448_ChaChaPoly_BLAKE2s", "Unsupported DH function '448'"),
+            ("Noise_XXpsk2_25519_Salsa_BLAKE2s", "Unsupported cipher 'Salsa'"),
+            ("Noise_XXpsk2_25519_ChaChaPoly_MD5", "Unsupported hash 'MD5'"),
+            ("Nois_XXpsk2_25519_ChaChaPoly_BLAKE2s", "Invalid Noise protocol"),
+        ];
+        for (pattern, expected) in cases {
+            match parse_noise_params(pattern) {
+                Err(NoiseError::HandshakeError(e)) => assert!(e.contains(expected), "{}: {}", pattern, e),
+                _ => panic!("{} should be refused", pattern),
+            }
+        }
+    }
+
+    fn upgrade_request(headers: &[(&str, &str)]) -> Request {
+        let mut builder = Request::builder().uri("ws://127.0.0.1/");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn check_origin_allows_listed_origins_and_non_browser_clients() {
+        let allowed: &[&str] = &["https://chat.example.com"];
+        let listed = upgrade_request(&[("Origin", "HTTPS://chat.example.com")]);
+        assert!(check_origin_against(&listed, Some(allowed)).is_ok());
+        // Only browsers send an Origin header
+        assert!(check_origin_against(&upgrade_request(&[]), Some(allowed)).is_ok());
+        let other = upgrade_request(&[("Origin", "https://evil.example")]);
+        assert!(check_origin_against(&other, None).is_ok());
+    }
+
+    #[test]
+    fn check_origin_refuses_unlisted_origins() {
+        let allowed: &[&str] = &["https://chat.example.com"];
+        let request = upgrade_request(&[("Origin", "https://evil.example")]);
+        let error = check_origin_against(&request, Some(allowed)).unwrap_err();
+        assert_eq!(error.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error.body().as_deref(), Some("Origin not allowed"));
+    }
+
+    #[test]
+    fn ip_slots_refuse_past_the_limit_and_return_on_drop() {
+        let connections = IpConnections::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let first = IpSlot::acquire_within(&connections, ip, Some(2)).expect("first slot");
+        let second = IpSlot::acquire_within(&connections, ip, Some(2)).expect("second slot");
+        assert!(IpSlot::acquire_within(&connections, ip, Some(2)).is_none());
+        // The limit is per address
+        let _elsewhere = IpSlot::acquire_within(&connections, other, Some(2)).expect("other IP");
+        drop(first);
+        let third = IpSlot::acquire_within(&connections, ip, Some(2)).expect("slot freed by drop");
+        drop(second);
+        assert_eq!(connections.lock().unwrap().get(&ip), Some(&1));
+        drop(third);
+        // An IP with no connections left is forgotten
+        assert!(!connections.lock().unwrap().contains_key(&ip));
+    }
+
+    #[test]
+    fn ip_slots_are_unlimited_without_a_limit() {
+        let connections = IpConnections::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let slots: Vec<IpSlot> = (0..100).filter_map(|_| IpSlot::acquire_within(&connections, ip, None)).collect();
+        assert_eq!(slots.len(), 100);
+    }
+
+    #[test]
+    fn name_bans_load_skips_comments_and_blank_lines() {
+        let path = std::env::temp_dir().join(format!("name-bans-{}.txt", std::process::id()));
+        std::fs::write(&path, "# banned names\n\n  Mallory  \nEVE\n   \n#not-a-name\n").unwrap();
+        let bans = NameBans::load(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut names: Vec<&str> = bans.names.iter().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(names, ["eve", "mallory"]);
+    }
+
+    #[test]
+    fn name_bans_load_treats_a_missing_file_as_empty() {
+        let path = std::env::temp_dir().join(format!("name-bans-missing-{}.txt", std::process::id()));
+        assert!(NameBans::load(path.to_str().unwrap()).unwrap().names.is_empty());
+    }
+
+    #[test]
+    fn name_bans_compare_case_insensitively() {
+        let mut bans = NameBans::default();
+        assert!(bans.set("Mallory", true).unwrap());
+        assert!(!bans.set("MALLORY", true).unwrap());
+        assert!(bans.is_banned("mallory"));
+        assert!(bans.is_banned("MaLLoRy"));
+        assert!(!bans.is_banned("Mallory2"));
+        assert!(bans.set("mallory", false).unwrap());
+        assert!(!bans.set("mallory", false).unwrap());
+        assert!(!bans.is_banned("Mallory"));
+    }
+
+    #[test]
+    fn rtt_starts_at_the_first_sample() {
+        let stats = ConnectionStats::default();
+        assert_eq!(stats.rtt_ms(), None);
+        stats.record_rtt(8000);
+        assert_eq!(stats.rtt_ms(), Some(8.0));
+    }
+
+    #[test]
+    fn rtt_weights_new_samples_by_an_eighth() {
+        let stats = ConnectionStats::default();
+        stats.record_rtt(8000);
+        stats.record_rtt(16000);
+        assert_eq!(stats.rtt_us.load(Ordering::Relaxed), 9000);
+        stats.record_rtt(1000);
+        assert_eq!(stats.rtt_us.load(Ordering::Relaxed), 8000);
+    }
+
+    #[test]
+    fn rtt_never_reads_as_unmeasured_after_a_sample() {
+        // 0 means no sample yet, so a sub-microsecond round trip is stored as 1
+        let stats = ConnectionStats::default();
+        stats.record_rtt(0);
+        assert_eq!(stats.rtt_us.load(Ordering::Relaxed), 1);
+        stats.record_rtt(0);
+        assert!(stats.rtt_ms().is_some());
+    }
+
+    #[test]
+    fn bound_protocol_refuses_a_downgraded_advertisement() {
+        let (mut initiator, mut responder) = handshake_states();
+        let mut message = vec![0u8; handshake_buffer_len().unwrap()];
+        let mut payload = vec![0u8; handshake_buffer_len().unwrap()];
+        let len = initiator.write_message(&[], &mut message).unwrap();
+        responder.read_message(&message[..len], &mut payload).unwrap();
+        // A peer (or anyone holding the PSK) naming an older version is refused
+        let len = responder.write_message(b"noise-chat-v1", &mut message).unwrap();
+        let len = initiator.read_message(&message[..len], &mut payload).unwrap();
+        match check_bound_protocol(&payload[..len]) {
+            Err(NoiseError::HandshakeError(e)) => {
+                assert!(e.contains("'noise-chat-v1'") && e.contains(SUBPROTOCOL), "{}", e)
+            }
+            _ => panic!("a downgraded advertisement should be refused"),
+        }
+    }
+
+    #[test]
+    fn bound_protocol_cannot_be_rewritten_in_transit() {
+        let (mut initiator, mut responder) = handshake_states();
+        let mut message = vec![0u8; handshake_buffer_len().unwrap()];
+        let mut payload = vec![0u8; handshake_buffer_len().unwrap()];
+        let len = initiator.write_message(&[], &mut message).unwrap();
+        responder.read_message(&message[..len], &mut payload).unwrap();
+        let len = responder.write_message(SUBPROTOCOL.as_bytes(), &mut message).unwrap();
+        // Flip a byte of the encrypted advertisement, just before its tag
+        message[len - TAG_LEN - 1] ^= 0x01;
+        assert!(initiator.read_message(&message[..len], &mut payload).is_err());
+    }
+
+    #[test]
+    fn bound_protocol_accepts_our_own_subprotocol() {
+        assert!(check_bound_protocol(SUBPROTOCOL.as_bytes()).is_ok());
+        assert!(check_bound_protocol(b"").is_err());
+    }
+
+    #[test]
+    fn decrypt_failure_diagnostics_describe_a_tampered_frame() {
+        let (mut client, mut server) = handshake_pair();
+        let first = client.encrypt(b"secret one").unwrap();
+        server.decrypt(&first).unwrap();
+        let mut tampered = client.encrypt(b"secret two").unwrap();
+        tampered[0] ^= 0x01;
+        assert!(server.decrypt(&tampered).is_err());
+        let diagnostics = server.decrypt_failure_diagnostics(&tampered);
+        // The failed frame doesn't consume a nonce, so the second one is still expected
+        assert!(diagnostics.starts_with(&format!("ciphertext_len={} expected_nonce=1 ", tampered.len())), "{}", diagnostics);
+        assert!(diagnostics.contains("sent_nonce=0"), "{}", diagnostics);
+        assert!(diagnostics.contains("cipher desync or wrong key"), "{}", diagnostics);
+        assert!(!diagnostics.contains("secret"));
+
+        let truncated = server.decrypt_failure_diagnostics(&tampered[..TAG_LEN - 1]);
+        assert!(truncated.contains("likely truncated"), "{}", truncated);
+        let oversized = server.decrypt_failure_diagnostics(&vec![0u8; NOISE_MAX_MESSAGE_LEN + 1]);
+        assert!(oversized.contains("likely not Noise ciphertext"), "{}", oversized);
+    }
+
+    #[tokio::test]
+    async fn responder_outcome_reports_the_negotiated_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut ws_sender, mut ws_receiver) = tokio_tungstenite::accept_async(stream).await.unwrap().split();
+            perform_noise_handshake_responder(&mut ws_sender, &mut ws_receiver, None)
+                .await
+                .map_err(|e| e.to_string())
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (ws, _) = tokio_tungstenite::client_async(format!("ws://{}/", addr), stream).await.unwrap();
+        let (mut ws_sender, mut ws_receiver) = ws.split();
+        let client_key = StaticKeypair::generate().unwrap();
+        let mut initiator = Builder::new(noise_params().unwrap())
+            .local_private_key(&client_key.private)
+            .psk(2, PSK)
+            .build_initiator()
+            .unwrap();
+        let mut buf = vec![0u8; handshake_buffer_len().unwrap()];
+        let len = initiator.write_message(&[], &mut buf).unwrap();
+        ws_sender.send(Message::Binary(buf[..len].to_vec())).await.unwrap();
+        let Some(Ok(Message::Binary(reply))) = ws_receiver.next().await else {
+            panic!("expected the server's handshake reply");
+        };
+        let len = initiator.read_message(&reply, &mut buf).unwrap();
+        assert_eq!(&buf[..len], SUBPROTOCOL.as_bytes());
+        let len = initiator.write_message(SUBPROTOCOL.as_bytes(), &mut buf).unwrap();
+        ws_sender.send(Message::Binary(buf[..len].to_vec())).await.unwrap();
+        let client_hash = initiator.get_handshake_hash().to_vec();
+
+        let outcome = server.await.unwrap().unwrap();
+        assert_eq!(outcome.pattern, noise_pattern());
+        assert_eq!(outcome.version, SUBPROTOCOL);
+        assert_eq!(outcome.psk_source, "static (compiled in, not QKD)");
+        assert_eq!(outcome.remote_static_fp, Some(fingerprint(&client_key.public)));
+        assert_eq!(outcome.session.handshake_hash, client_hash);
+    }
+
+    const NEWER_CONTROL: &str = r#"{"sender":"Alice","content":"[poll: lunch?]","control":{"type":"poll","options":["yes","no"]}}"#;
+
+    #[test]
+    fn unknown_control_relays_as_chat_when_lenient() {
+        let mut msg: ChatMessage = serde_json::from_str(NEWER_CONTROL).unwrap();
+        assert!(matches!(msg.control, Some(ControlMessage::Unknown)));
+        assert_eq!(strip_unknown_control(&mut msg, false), Ok(()));
+        assert!(msg.control.is_none());
+        assert_eq!(msg.content, "[poll: lunch?]");
+    }
+
+    #[test]
+    fn unknown_control_is_refused_when_strict() {
+        let mut msg: ChatMessage = serde_json::from_str(NEWER_CONTROL).unwrap();
+        let reason = strip_unknown_control(&mut msg, true).unwrap_err();
+        assert!(reason.contains("does not support its type"), "{}", reason);
+        // Known types are never affected by the policy
+        let mut direct: ChatMessage =
+            serde_json::from_str(r#"{"sender":"Alice","content":"hi","control":{"type":"direct","to":"Bob"}}"#).unwrap();
+        assert_eq!(strip_unknown_control(&mut direct, true), Ok(()));
+        assert!(matches!(direct.control, Some(ControlMessage::Direct { .. })));
+    }
+
+    #[test]
+    fn rich_payload_survives_the_relay_within_the_limit() {
+        let wire = r#"{"sender":"","content":"[application/json payload]","rich":{"content_type":"application/json","data":{"n":[1,2,3]}}}"#;
+        let msg: ChatMessage = serde_json::from_str(wire).unwrap();
+        let rich = msg.rich.as_ref().expect("rich payload");
+        // {"n":[1,2,3]} is 13 bytes
+        assert_eq!(rich.problem(13), None);
+        assert_eq!(rich.problem(12).as_deref(), Some("payload is 13 bytes, the limit is 12"));
+        let relayed: serde_json::Value = serde_json::to_value(&msg).unwrap();
+        assert_eq!(relayed["rich"]["content_type"], "application/json");
+        assert_eq!(relayed["rich"]["data"]["n"][2], 3);
+    }
+
+    #[test]
+    fn negotiate_subprotocol_echoes_ours_when_offered() {
+        let offered = format!("noise-chat-v1, {}", SUBPROTOCOL);
+        let request = upgrade_request(&[("Sec-WebSocket-Protocol", offered.as_str())]);
+        let response = negotiate_subprotocol(&request, Response::new(())).unwrap();
+        assert_eq!(response.headers().get(SEC_WEBSOCKET_PROTOCOL).unwrap(), SUBPROTOCOL);
+    }
+
+    #[test]
+    fn negotiate_subprotocol_refuses_a_missing_or_wrong_offer() {
+        for headers in [&[][..], &[("Sec-WebSocket-Protocol", "noise-chat-v1")][..]] {
+            let error = negotiate_subprotocol(&upgrade_request(headers), Response::new(())).unwrap_err();
+            assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(
+                error.body().as_deref(),
+                Some(format!("Unsupported subprotocol, expected {}", SUBPROTOCOL).as_str())
+            );
+        }
+    }
+
+    #[test]
+    fn negotiate_subprotocol_checks_the_advertised_noise_protocol() {
+        let ours = noise_pattern();
+        let matching = upgrade_request(&[("Sec-WebSocket-Protocol", SUBPROTOCOL), ("x-noise-protocol", ours.as_str())]);
+        assert!(negotiate_subprotocol(&matching, Response::new(())).is_ok());
+        // No server accepts NN, so this never matches ours
+        let other = upgrade_request(&[
+            ("Sec-WebSocket-Protocol", SUBPROTOCOL),
+            ("x-noise-protocol", "Noise_NN_25519_ChaChaPoly_BLAKE2s"),
+        ]);
+        let error = negotiate_subprotocol(&other, Response::new(())).unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        let body = error.body().as_deref().unwrap();
+        assert!(body.starts_with("Noise protocol mismatch: handshake pattern (and PSK position) NN vs "), "{}", body);
+    }
+
+    #[test]
+    fn handshake_buffer_len_fits_the_largest_message_up_to_the_noise_limit() {
+        let pattern = "Noise_XXpsk2_25519_ChaChaPoly_BLAKE2s";
+        // Ephemeral key, encrypted static key and encrypted payload
+        let overhead = 32 + (32 + TAG_LEN) + TAG_LEN;
+        assert_eq!(handshake_buffer_len_for(pattern, 64).unwrap(), overhead + 64);
+        let largest = NOISE_MAX_MESSAGE_LEN - overhead;
+        assert_eq!(handshake_buffer_len_for(pattern, largest).unwrap(), NOISE_MAX_MESSAGE_LEN);
+        match handshake_buffer_len_for(pattern, largest + 1) {
+            Err(NoiseError::HandshakeError(e)) => assert!(e.contains("over the Noise limit of 65535"), "{}", e),
+            _ => panic!("one byte over the limit should be refused"),
+        }
+    }
+
+    #[test]
+    fn handshake_buffer_len_refuses_what_it_cannot_size() {
+        assert!(handshake_buffer_len_for("Noise_XXpsk2_448_ChaChaPoly_BLAKE2s", 64).is_err());
+        assert!(handshake_buffer_len_for("Noise_XXpsk2_25519_ChaChaPoly_BLAKE2s", SUBPROTOCOL.len() - 1).is_err());
+        assert!(handshake_buffer_len_for("Noise_XXpsk2_25519_ChaChaPoly_BLAKE2s", SUBPROTOCOL.len()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_panicking_connection_task_is_cleaned_up() {
+        let clients = Mutex::new(Roster::default());
+        clients.lock().await.join(1, client_info("Alice")).unwrap();
+        let (broadcast_tx, mut broadcast_rx) = broadcast::channel(8);
+        let sibling = tokio::spawn(std::future::pending::<()>());
+        let sibling_abort = sibling.abort_handle();
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+
+        let panicked = supervise_tasks(
+            vec![("receive", tokio::spawn(async { panic!("injected") })), ("broadcast", sibling)],
+            1,
+            addr,
+        )
+        .await;
+        assert!(panicked);
+        tokio::task::yield_now().await;
+        assert!(sibling_abort.is_finished());
+
+        remove_client(&clients, 1, &broadcast_tx).await;
+        assert!(clients.lock().await.clients.is_empty());
+        let notice = broadcast_rx.recv().await.unwrap();
+        assert!(matches!(notice.control, Some(ControlMessage::RosterLeave { ref name, .. }) if name == "Alice"));
+    }
+
+    #[tokio::test]
+    async fn a_task_ending_normally_is_not_reported_as_a_panic() {
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let pending = tokio::spawn(std::future::pending::<()>());
+        let pending_abort = pending.abort_handle();
+        assert!(!supervise_tasks(vec![("ping", tokio::spawn(async {})), ("receive", pending)], 1, addr).await);
+        tokio::task::yield_now().await;
+        assert!(pending_abort.is_finished());
+    }
+
+    fn server_state() -> ServerState {
+        ServerState {
+            broadcast_tx: broadcast::channel(64).0,
+            server_cmd_tx: broadcast::channel(64).0,
+            clients: Arc::new(Mutex::new(Roster::default())),
+            client_counter: Arc::new(Mutex::new(0)),
+            static_key: Arc::new(Mutex::new(None)),
+            message_filter: Arc::new(NoFilter),
+            next_message_id: Arc::new(AtomicU64::new(1)),
+            ip_connections: IpConnections::default(),
+            handshake_slots: Arc::new(Semaphore::new(MAX_CONCURRENT_HANDSHAKES)),
+            stats: Arc::new(ServerStats::new()),
+            handshake_failures: Arc::new(std::sync::Mutex::new(HandshakeFailures::default())),
+            admin_auth: Arc::new(DenyAll),
+            observer_auth: Arc::new(DenyAll),
+            draining: Arc::new(AtomicBool::new(false)),
+            name_bans: Arc::new(std::sync::Mutex::new(NameBans::default())),
+        }
+    }
+
+    // Accepts a single connection on a loopback port and serves it
+    async fn serve_one(state: ServerState) -> (SocketAddr, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            handle_connection(stream, peer, state).await;
+        });
+        (addr, server)
+    }
+
+    // Upgrades and completes the Noise handshake the way the client binary does
+    async fn connect_client(addr: SocketAddr) -> (WsSender, WsReceiver, NoiseSession) {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let mut request = format!("ws://{}/", addr).into_client_request().unwrap();
+        request.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(SUBPROTOCOL));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (ws, _) = tokio_tungstenite::client_async(request, stream).await.unwrap();
+        let (mut ws_sender, mut ws_receiver) = ws.split();
+        let (mut initiator, _) = handshake_states();
+        let mut buf = vec![0u8; handshake_buffer_len().unwrap()];
+        let len = initiator.write_message(&[], &mut buf).unwrap();
+        ws_sender.send(Message::Binary(buf[..len].to_vec())).await.unwrap();
+        let Some(Ok(Message::Binary(reply))) = ws_receiver.next().await else {
+            panic!("expected the server's handshake reply");
+        };
+        initiator.read_message(&reply, &mut buf).unwrap();
+        let len = initiator.write_message(SUBPROTOCOL.as_bytes(), &mut buf).unwrap();
+        ws_sender.send(Message::Binary(buf[..len].to_vec())).await.unwrap();
+        let handshake_hash = initiator.get_handshake_hash().to_vec();
+        let session = NoiseSession::new(initiator.into_transport_mode().unwrap(), handshake_hash);
+        (ws_sender, ws_receiver, session)
+    }
+
+    async fn next_message(ws_receiver: &mut WsReceiver, session: &mut NoiseSession) -> ChatMessage {
+        match ws_receiver.next().await {
+            Some(Ok(Message::Binary(frame))) => serde_json::from_slice(&session.decrypt(&frame).unwrap()).unwrap(),
+            other => panic!("expected a message, got {:?}", other),
+        }
+    }
+
+    // Connects and answers the name prompt, returning once the server lists the client
+    async fn join_client(addr: SocketAddr, state: &ServerState, name: &str) -> (WsSender, WsReceiver, NoiseSession) {
+        let (mut ws_sender, mut ws_receiver, mut session) = connect_client(addr).await;
+        assert_eq!(next_message(&mut ws_receiver, &mut session).await.content, "Please enter your name:");
+        let reply = session.encrypt_message(&text(name)).unwrap();
+        ws_sender.send(Message::Binary(reply)).await.unwrap();
+        while !matches!(state.clients.lock().await.holder(name), NameHolder::Connected) {
+            tokio::task::yield_now().await;
+        }
+        (ws_sender, ws_receiver, session)
+    }
+
+    // Skips pings and other senders' messages, batched or not, up to the next one from `sender`
+    async fn next_from(ws_receiver: &mut WsReceiver, session: &mut NoiseSession, sender: &str) -> ChatMessage {
+        loop {
+            let frame = match ws_receiver.next().await {
+                Some(Ok(Message::Binary(frame))) => frame,
+                Some(Ok(Message::Ping(_))) => continue,
+                other => panic!("expected a message from {}, got {:?}", sender, other),
+            };
+            for part in unpack(&session.decrypt(&frame).unwrap()) {
+                let message: ChatMessage = serde_json::from_slice(&part).unwrap();
+                if message.sender == sender {
+                    return message;
+                }
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_client_that_stalls_before_sending_its_name_is_dropped() {
+        let state = server_state();
+        let (addr, server) = serve_one(state.clone()).await;
+        let started = Instant::now();
+        let (_ws_sender, mut ws_receiver, mut session) = connect_client(addr).await;
+        let prompt = next_message(&mut ws_receiver, &mut session).await;
+        assert_eq!(prompt.content, "Please enter your name:");
+
+        // No name is ever sent; the paused clock runs on to the timeout
+        server.await.unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(HANDSHAKE_TIMEOUT_SECS));
+        assert_eq!(state.stats.handshake_timeouts.load(Ordering::Relaxed), 1);
+        assert!(state.clients.lock().await.clients.is_empty());
+        assert_eq!(state.handshake_slots.available_permits(), MAX_CONCURRENT_HANDSHAKES);
+        assert!(!matches!(ws_receiver.next().await, Some(Ok(Message::Binary(_)))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_connection_waiting_too_long_for_a_handshake_slot_is_refused() {
+        let state = ServerState {
+            handshake_slots: Arc::new(Semaphore::new(1)),
+            ..server_state()
+        };
+        let _busy = Arc::clone(&state.handshake_slots).acquire_owned().await.unwrap();
+        let (addr, server) = serve_one(state.clone()).await;
+        let started = Instant::now();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        server.await.unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(HANDSHAKE_QUEUE_SECS));
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", response);
+        assert!(response.contains(&format!("Retry-After: {}\r\n", HANDSHAKE_QUEUE_SECS)), "{}", response);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_queued_connection_proceeds_once_a_slot_frees() {
+        let state = ServerState {
+            handshake_slots: Arc::new(Semaphore::new(1)),
+            ..server_state()
+        };
+        let busy = Arc::clone(&state.handshake_slots).acquire_owned().await.unwrap();
+        let (addr, _server) = serve_one(state.clone()).await;
+        let client = tokio::spawn(async move {
+            let (ws_sender, mut ws_receiver, mut session) = connect_client(addr).await;
+            let prompt = next_message(&mut ws_receiver, &mut session).await;
+            (prompt, ws_sender, ws_receiver)
+        });
+        tokio::time::sleep(Duration::from_secs(HANDSHAKE_QUEUE_SECS - 1)).await;
+        assert!(!client.is_finished());
+        // Real time from here on, so loopback I/O can't let the paused clock
+        // jump ahead to the handshake timeout
+        tokio::time::resume();
+        drop(busy);
+        let (prompt, _ws_sender, _ws_receiver) = client.await.unwrap();
+        assert_eq!(prompt.content, "Please enter your name:");
+        // The queued connection now holds the only slot until it joins
+        assert_eq!(state.handshake_slots.available_permits(), 0);
+    }
+
+    #[tokio::test]
+    async fn the_console_runs_its_lines_and_stops_at_end_of_input() {
+        let admin = admin_context();
+        let mut commands = admin.server_cmd_tx.subscribe();
+        assert_eq!(run_console(&b"hello\n\n//quit\n"[..], admin.clone()).await, ConsoleEnd::Eof);
+        assert_eq!(commands.try_recv().unwrap().message.content, "hello");
+        assert_eq!(commands.try_recv().unwrap().message.content, "/quit");
+        assert!(commands.try_recv().is_err());
+        // Stopping the console leaves the server's state alone
+        assert!(!admin.draining.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn the_console_tells_a_read_failure_from_end_of_input() {
+        let admin = admin_context();
+        let mut commands = admin.server_cmd_tx.subscribe();
+        assert_eq!(run_console(&b"before\n\xff\xfe\nafter\n"[..], admin).await, ConsoleEnd::Failed);
+        assert_eq!(commands.try_recv().unwrap().message.content, "before");
+        assert!(commands.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn a_server_notice_overtakes_queued_broadcasts_without_reordering_them() {
+        let (mut client, server) = handshake_pair();
+        let written = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = Arc::clone(&written);
+        // Every frame yields before it lands, like a socket slow to drain
+        let sink = Box::pin(futures_util::sink::unfold((), move |(), frame: Message| {
+            let log = Arc::clone(&log);
+            async move {
+                tokio::task::yield_now().await;
+                log.lock().unwrap().push(frame);
+                Ok::<_, tokio_tungstenite::tungstenite::Error>(())
+            }
+        }));
+        let session = Mutex::new(server);
+        let sender = Mutex::new(sink);
+        let notices = NoticePriority::default();
+        let stats = ConnectionStats::default();
+        let frames = (0..4).map(|i| serde_json::to_vec(&text(&format!("chat {}", i))).unwrap()).collect();
+
+        let batch = write_broadcasts(frames, &session, &sender, &notices, &stats, true);
+        let notice = async {
+            // Let the batch start writing first
+            tokio::task::yield_now().await;
+            let _pending = notices.announce();
+            let encrypted = session.lock().await.encrypt_message(&text("notice")).unwrap();
+            sender.lock().await.send(Message::Binary(encrypted)).await.unwrap();
+        };
+        let (ok, ()) = tokio::join!(batch, notice);
+        assert!(ok);
+        assert!(!notices.any_pending());
+
+        let order: Vec<String> = written
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|frame| {
+                let plaintext = client.decrypt(&frame.clone().into_data()).unwrap();
+                serde_json::from_slice::<ChatMessage>(&plaintext).unwrap().content
+            })
+            .collect();
+        let chat: Vec<&String> = order.iter().filter(|content| content.starts_with("chat")).collect();
+        assert_eq!(chat, ["chat 0", "chat 1", "chat 2", "chat 3"]);
+        let notice_at = order.iter().position(|content| content == "notice").unwrap();
+        assert!(notice_at > 0 && notice_at < order.len() - 1, "{:?}", order);
+    }
+
+    #[tokio::test]
+    async fn waiting_for_notices_returns_at_once_once_the_last_is_sent() {
+        let notices = NoticePriority::default();
+        drop(notices.announce());
+        assert!(!notices.any_pending());
+        // Returns at once rather than waiting for a notification
+        notices.wait_until_sent().await;
+    }
+
+    #[tokio::test]
+    async fn sys_broadcasts_an_unattributed_system_notice() {
+        let admin = admin_context();
+        let mut commands = admin.server_cmd_tx.subscribe();
+        assert_eq!(
+            run_admin_command("/sys  Restarting at noon ", &admin).await.as_deref(),
+            Some("System notice: Restarting at noon")
+        );
+        let cmd = commands.try_recv().unwrap();
+        assert!(cmd.target.is_none());
+        assert_eq!(
+            serde_json::to_string(&cmd.message).unwrap(),
+            r#"{"sender":"","content":"Restarting at noon","control":{"type":"system"}}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn drain_and_ready_flip_readiness() {
+        let admin = admin_context();
+        assert_eq!(
+            run_admin_command("/drain", &admin).await.unwrap(),
+            format!(
+                "Draining: refusing new connections; exiting once all clients leave or after {} s (/ready cancels)",
+                DRAIN_TIMEOUT_SECS
+            )
+        );
+        assert!(admin.draining.load(Ordering::Relaxed));
+        assert_eq!(run_admin_command("/drain", &admin).await.as_deref(), Some("Already draining"));
+        assert!(run_admin_command("/stats", &admin).await.unwrap().contains("Draining:          refusing new connections"));
+        // Put back in service before the spawned drain first checks, so it stands down
+        assert_eq!(run_admin_command("/ready", &admin).await.as_deref(), Some("Accepting new connections again"));
+        assert!(!admin.draining.load(Ordering::Relaxed));
+        assert_eq!(run_admin_command("/ready", &admin).await.as_deref(), Some("Not draining"));
+        assert!(!run_admin_command("/stats", &admin).await.unwrap().contains("Draining"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_drain_ends_once_the_last_client_leaves() {
+        let admin = admin_context();
+        admin.draining.store(true, Ordering::Relaxed);
+        admin.clients.lock().await.clients.insert(1, client_info("Alice"));
+        let mut gone = client_info("Bob");
+        // Waiting out the reconnect grace period, so not waited for
+        gone.connected = false;
+        admin.clients.lock().await.clients.insert(2, gone);
+
+        let clients = Arc::clone(&admin.clients);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            clients.lock().await.clients.remove(&1);
+        });
+        let started = Instant::now();
+        assert_eq!(drain_within(&admin, Duration::from_secs(DRAIN_TIMEOUT_SECS)).await, DrainEnd::Drained);
+        assert!(started.elapsed() >= Duration::from_secs(30));
+        assert!(started.elapsed() < Duration::from_secs(DRAIN_TIMEOUT_SECS));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ready_cancels_a_drain_in_progress() {
+        let admin = admin_context();
+        admin.draining.store(true, Ordering::Relaxed);
+        admin.clients.lock().await.clients.insert(1, client_info("Alice"));
+        let mut commands = admin.server_cmd_tx.subscribe();
+
+        let draining = Arc::clone(&admin.draining);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            draining.store(false, Ordering::Relaxed);
+        });
+        assert_eq!(drain_within(&admin, Duration::from_secs(DRAIN_TIMEOUT_SECS)).await, DrainEnd::Cancelled);
+        // Connected clients were left alone
+        assert!(commands.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_drain_that_times_out_sends_a_shutdown_notice() {
+        let admin = admin_context();
+        admin.draining.store(true, Ordering::Relaxed);
+        admin.clients.lock().await.clients.insert(1, client_info("Alice"));
+        let mut commands = admin.server_cmd_tx.subscribe();
+
+        let started = Instant::now();
+        assert_eq!(drain_within(&admin, Duration::from_secs(60)).await, DrainEnd::TimedOut);
+        // Alice never left, so the notice period ran out as well
+        assert!(started.elapsed() >= Duration::from_secs(60 + SHUTDOWN_NOTICE_SECS));
+        let cmd = commands.try_recv().unwrap();
+        assert!(cmd.target.is_none());
+        assert!(matches!(
+            cmd.message.control,
+            Some(ControlMessage::Disconnect { reason: DisconnectReason::Shutdown, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_draining_server_refuses_new_connections_and_keeps_serving_joined_ones() {
+        let state = server_state();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(accept_loop(listener, state.clone()));
+
+        let (_ws_sender, mut ws_receiver, mut session) = join_client(addr, &state, "Alice").await;
+
+        state.draining.store(true, Ordering::Relaxed);
+        let mut refused = TcpStream::connect(addr).await.unwrap();
+        let mut response = String::new();
+        refused.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", response);
+
+        // Alice is still served
+        let mut relayed = text("still here");
+        relayed.sender = "Bob".to_string();
+        state.broadcast_tx.send(relayed).unwrap();
+        assert_eq!(next_from(&mut ws_receiver, &mut session, "Bob").await.content, "still here");
+        assert_eq!(connected_clients(&state.clients).await, 1);
+        server.abort();
+    }
+
+    // Stands in for a payload serde_json can't represent
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("payload has no JSON form"))
+        }
+    }
+
+    #[test]
+    fn a_message_that_fails_to_serialize_is_reported_and_uses_no_nonce() {
+        let (mut client, mut server) = handshake_pair();
+        let err = server.encrypt_message(&Unserializable).unwrap_err();
+        assert!(matches!(err, NoiseError::SerializationError(_)));
+        assert_eq!(err.to_string(), "Serialization error: payload has no JSON form");
+
+        // The failed message took no nonce, so the next one still decrypts
+        let encrypted = server.encrypt_message(&text("after")).unwrap();
+        let decrypted: ChatMessage = serde_json::from_slice(&client.decrypt(&encrypted).unwrap()).unwrap();
+        assert_eq!(decrypted.content, "after");
+    }
+
+    // Compares FLUSH_EACH_FRAME settings for bursts over a loopback WebSocket.
+    // Not run by default:
+    //   cargo test --release --bin server flush_benchmark -- --ignored --nocapture
+    #[tokio::test]
+    #[ignore]
+    async fn flush_benchmark() {
+        const BURST: usize = 100;
+        const ROUNDS: usize = 200;
+
+        for flush_each_frame in [true, false] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let reader = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (_, mut ws_receiver) = tokio_tungstenite::accept_async(stream).await.unwrap().split();
+                let mut frames = 0;
+                while frames < BURST * ROUNDS {
+                    if let Some(Ok(Message::Binary(_))) = ws_receiver.next().await {
+                        frames += 1;
+                    }
+                }
+            });
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let (ws, _) = tokio_tungstenite::client_async(format!("ws://{}/", addr), stream).await.unwrap();
+            let (ws_sender, _ws_receiver) = ws.split();
+            let (_, server) = handshake_pair();
+            let session = Mutex::new(server);
+            let sender = Mutex::new(ws_sender);
+            let notices = NoticePriority::default();
+            let stats = ConnectionStats::default();
+            let payload = serde_json::to_vec(&text(&"x".repeat(200))).unwrap();
+
+            let started = std::time::Instant::now();
+            for _ in 0..ROUNDS {
+                let frames = vec![payload.clone(); BURST];
+                assert!(write_broadcasts(frames, &session, &sender, &notices, &stats, flush_each_frame).await);
+            }
+            reader.await.unwrap();
+            let elapsed = started.elapsed();
+            println!(
+                "flush_each_frame = {:5}: {} bursts of {} frames in {:?} ({:.2} us per frame)",
+                flush_each_frame,
+                ROUNDS,
+                BURST,
+                elapsed,
+                elapsed.as_secs_f64() * 1e6 / (BURST * ROUNDS) as f64
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_burst_that_finds_a_deep_queue_counts_as_a_slow_delivery() {
+        let state = server_state();
+        let (addr, _server) = serve_one(state.clone()).await;
+        let (_ws_sender, mut ws_receiver, mut session) = join_client(addr, &state, "Alice").await;
+
+        // Queued in one go, so the broadcast task finds them all waiting and
+        // drains them as one burst, leaving nothing queued behind it
+        for i in 0..=SLOW_QUEUE_DEPTH {
+            let mut relayed = text(&i.to_string());
+            relayed.sender = "Bob".to_string();
+            state.broadcast_tx.send(relayed).unwrap();
+        }
+        for i in 0..=SLOW_QUEUE_DEPTH {
+            assert_eq!(next_from(&mut ws_receiver, &mut session, "Bob").await.content, i.to_string());
+        }
+        let stats = Arc::clone(&state.clients.lock().await.clients.values().next().unwrap().stats);
+        assert_eq!(stats.slow_sends.load(Ordering::Relaxed), 1);
+    }
+}